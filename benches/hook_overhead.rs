@@ -0,0 +1,156 @@
+//! hook的单次调用开销
+//!
+//! 基准程序链接libmemleak，进程内的malloc/free都会经过hook。分别测量：
+//! - 直接调用libc原始函数，作为基线
+//! - 经过hook、`memleak_disable`关闭追踪时
+//! - 经过hook、正常追踪时（包含调用栈采集和存活分配表维护）
+//!
+//! 另外对比hook取得原始函数的两种方式：改为原子变量之前的`lazy_static`，
+//! 以及现在的`AtomicPtr`。两者都只包装一层对libc原始函数的调用，差值即查找本身的开销。
+//!
+//! 运行：`MEMLEAK_REPORT=0 cargo bench --bench hook_overhead`
+
+extern crate memleak;
+
+use lazy_static::lazy_static;
+use libc::{c_void, size_t};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::time::Instant;
+
+extern "C" {
+    fn memleak_enable();
+    fn memleak_disable();
+}
+
+type MallocFn = unsafe extern "C" fn(size: size_t) -> *mut c_void;
+type FreeFn = unsafe extern "C" fn(ptr: *mut c_void);
+
+/// 每轮的malloc/free次数
+const ITERATIONS: usize = 1_000_000;
+
+/// 测量轮数，取最快一轮排除调度干扰
+const ROUNDS: usize = 5;
+
+/// 分配大小，落在glibc的tcache范围内，原始分配本身足够快
+const SIZE: usize = 64;
+
+/// 从libc.so.6中直接取得原始函数，绕过hook
+unsafe fn libc_originals() -> (MallocFn, FreeFn) {
+    let handle = libc::dlopen(c"libc.so.6".as_ptr(), libc::RTLD_NOW | libc::RTLD_NOLOAD);
+    assert!(!handle.is_null(), "libc.so.6 is not loaded");
+    let malloc = libc::dlsym(handle, c"malloc".as_ptr());
+    let free = libc::dlsym(handle, c"free".as_ptr());
+    assert!(!malloc.is_null() && !free.is_null());
+    (
+        std::mem::transmute::<*mut c_void, MallocFn>(malloc),
+        std::mem::transmute::<*mut c_void, FreeFn>(free),
+    )
+}
+
+lazy_static! {
+    /// 改为原子变量之前的写法
+    static ref LAZY_ORIGINALS: (MallocFn, FreeFn) = unsafe { libc_originals() };
+}
+
+/// 现在的写法：`Original<F>`中的原子变量，第一次使用时解析
+static ATOMIC_MALLOC: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+static ATOMIC_FREE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+#[inline(always)]
+unsafe fn atomic_originals() -> (MallocFn, FreeFn) {
+    let (mut malloc, mut free) = (
+        ATOMIC_MALLOC.load(Ordering::Relaxed),
+        ATOMIC_FREE.load(Ordering::Relaxed),
+    );
+    if malloc.is_null() || free.is_null() {
+        let (real_malloc, real_free) = libc_originals();
+        malloc = real_malloc as *mut c_void;
+        free = real_free as *mut c_void;
+        ATOMIC_MALLOC.store(malloc, Ordering::Relaxed);
+        ATOMIC_FREE.store(free, Ordering::Relaxed);
+    }
+    (
+        std::mem::transmute::<*mut c_void, MallocFn>(malloc),
+        std::mem::transmute::<*mut c_void, FreeFn>(free),
+    )
+}
+
+#[inline(never)]
+unsafe extern "C" fn lazy_malloc(size: size_t) -> *mut c_void {
+    (LAZY_ORIGINALS.0)(size)
+}
+
+#[inline(never)]
+unsafe extern "C" fn lazy_free(ptr: *mut c_void) {
+    (LAZY_ORIGINALS.1)(ptr)
+}
+
+#[inline(never)]
+unsafe extern "C" fn atomic_malloc(size: size_t) -> *mut c_void {
+    atomic_originals().0(size)
+}
+
+#[inline(never)]
+unsafe extern "C" fn atomic_free(ptr: *mut c_void) {
+    atomic_originals().1(ptr)
+}
+
+/// 测量一对malloc/free的平均耗时（纳秒）
+fn measure(malloc: MallocFn, free: FreeFn) -> f64 {
+    let mut best = f64::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            unsafe {
+                let ptr = malloc(black_box(SIZE));
+                free(black_box(ptr));
+            }
+        }
+        best = best.min(start.elapsed().as_nanos() as f64 / ITERATIONS as f64);
+    }
+    best
+}
+
+fn report(name: &str, nanos: f64, baseline: f64) {
+    println!(
+        "{name:<24} {nanos:>8.1} ns/pair  (+{:.1} ns)",
+        nanos - baseline
+    );
+}
+
+fn main() {
+    let (real_malloc, real_free) = unsafe { libc_originals() };
+    let hooked_malloc: MallocFn = libc::malloc;
+    let hooked_free: FreeFn = libc::free;
+
+    // 预热：触发原始函数解析和线程登记
+    measure(hooked_malloc, hooked_free);
+
+    let baseline = measure(real_malloc, real_free);
+    report("libc", baseline, baseline);
+    report(
+        "lookup, lazy_static",
+        measure(lazy_malloc, lazy_free),
+        baseline,
+    );
+    report(
+        "lookup, atomic",
+        measure(atomic_malloc, atomic_free),
+        baseline,
+    );
+
+    unsafe { memleak_disable() };
+    report(
+        "hook, disabled",
+        measure(hooked_malloc, hooked_free),
+        baseline,
+    );
+    unsafe { memleak_enable() };
+
+    report(
+        "hook, tracking",
+        measure(hooked_malloc, hooked_free),
+        baseline,
+    );
+}
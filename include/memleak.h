@@ -0,0 +1,80 @@
+/*
+ * libmemleak.so 控制接口
+ *
+ * 这些函数只在通过LD_PRELOAD加载libmemleak.so时存在。被测程序不应直接链接，
+ * 而是用memleak_api_load()通过dlsym查找；未预加载时对应的函数指针为NULL，
+ * 调用前判空即可。
+ *
+ *     struct memleak_api api;
+ *     memleak_api_load(&api);
+ *     if (api.snapshot) api.snapshot();
+ */
+#ifndef MEMLEAK_H
+#define MEMLEAK_H
+
+#include <dlfcn.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* 恢复/暂停记录新的分配；暂停前已记录的块仍正常匹配释放 */
+void memleak_enable(void);
+void memleak_disable(void);
+
+/* 拍摄一次堆快照，返回快照编号（从1开始）；只保留最近的64次快照 */
+uint32_t memleak_snapshot(void);
+
+/* 把当前存活分配表写到文件，path为NULL时写到带时间戳的默认文件；成功返回0，失败返回-1 */
+int memleak_dump(const char *path);
+
+/* 把快照from到to的对比写到文件，path为NULL时写到带时间戳的默认文件；成功返回0，失败返回-1 */
+int memleak_diff(uint32_t from, uint32_t to, const char *path);
+
+/* 向Tracy发送一条消息 */
+void memleak_message(const char *message);
+
+/* 在begin/end之间忽略当前线程的分配，可以嵌套 */
+void memleak_ignore_begin(void);
+void memleak_ignore_end(void);
+
+/* 通过dlsym查找到的接口，未预加载libmemleak.so时各成员为NULL */
+struct memleak_api {
+    void (*enable)(void);
+    void (*disable)(void);
+    uint32_t (*snapshot)(void);
+    int (*dump)(const char *path);
+    int (*diff)(uint32_t from, uint32_t to, const char *path);
+    void (*message)(const char *message);
+    void (*ignore_begin)(void);
+    void (*ignore_end)(void);
+};
+
+static inline void *memleak_api_symbol(void *global, const char *name)
+{
+    return global ? dlsym(global, name) : NULL;
+}
+
+/* 在全局符号范围（主程序及预加载的库）中查找，只用到POSIX的dlopen/dlsym */
+static inline void memleak_api_load(struct memleak_api *api)
+{
+    void *global = dlopen(NULL, RTLD_LAZY);
+    *(void **)&api->enable = memleak_api_symbol(global, "memleak_enable");
+    *(void **)&api->disable = memleak_api_symbol(global, "memleak_disable");
+    *(void **)&api->snapshot = memleak_api_symbol(global, "memleak_snapshot");
+    *(void **)&api->dump = memleak_api_symbol(global, "memleak_dump");
+    *(void **)&api->diff = memleak_api_symbol(global, "memleak_diff");
+    *(void **)&api->message = memleak_api_symbol(global, "memleak_message");
+    *(void **)&api->ignore_begin = memleak_api_symbol(global, "memleak_ignore_begin");
+    *(void **)&api->ignore_end = memleak_api_symbol(global, "memleak_ignore_end");
+    if (global)
+        dlclose(global);
+}
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MEMLEAK_H */
@@ -0,0 +1,90 @@
+//! 符号解析期间使用的引导分配器
+//!
+//! 原始分配函数通过`dlsym`解析，而部分glibc/musl版本的`dlsym`自身会调用
+//! `calloc`/`malloc`。此时原始函数指针还不存在，这些分配改由一块静态内存按顺序
+//! 切分提供。引导分配的内存永不回收，`free`遇到时直接忽略，`realloc`遇到时
+//! 把内容复制到新分配的块中，它们都不会被交给真正的分配器。
+
+use libc::c_void;
+use std::cell::{Cell, UnsafeCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 引导区大小，`dlsym`期间的分配通常只有几百字节
+const ARENA_SIZE: usize = 64 * 1024;
+
+/// 每个块前记录块大小的头部，保持16字节对齐
+const HEADER_SIZE: usize = 16;
+
+#[repr(C, align(4096))]
+struct Arena(UnsafeCell<[u8; ARENA_SIZE]>);
+
+// 各块的切分通过原子偏移量互斥，同一字节不会被两个调用者同时持有
+unsafe impl Sync for Arena {}
+
+static ARENA: Arena = Arena(UnsafeCell::new([0; ARENA_SIZE]));
+
+/// 下一个空闲字节相对引导区起点的偏移
+static OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// 当前线程正在通过`dlsym`解析原始函数的层数
+    static RESOLVING: Cell<u32> = const { Cell::new(0) };
+}
+
+/// 当前线程是否处于符号解析中，此时分配必须由引导区提供
+#[inline]
+pub(crate) fn resolving() -> bool {
+    RESOLVING.with(|depth| depth.get() > 0)
+}
+
+/// 在符号解析状态下执行`f`
+pub(crate) fn resolve<R>(f: impl FnOnce() -> R) -> R {
+    RESOLVING.with(|depth| depth.set(depth.get() + 1));
+    let result = f();
+    RESOLVING.with(|depth| depth.set(depth.get() - 1));
+    result
+}
+
+#[inline]
+fn base() -> usize {
+    ARENA.0.get() as usize
+}
+
+/// 指针是否来自引导区
+#[inline]
+pub(crate) fn contains(ptr: *const c_void) -> bool {
+    (ptr as usize).wrapping_sub(base()) < ARENA_SIZE
+}
+
+/// 从引导区分配`size`字节，按`align`对齐；引导区耗尽时返回空指针
+///
+/// 静态内存初始为零且不会复用，分配结果天然满足`calloc`的要求。
+pub(crate) fn alloc(size: usize, align: usize) -> *mut c_void {
+    let align = align.max(HEADER_SIZE);
+    if !align.is_power_of_two() {
+        return std::ptr::null_mut();
+    }
+    let mut offset = OFFSET.load(Ordering::Relaxed);
+    loop {
+        // 块起点按要求对齐，头部紧挨在块起点之前
+        let start = (base() + offset + HEADER_SIZE).next_multiple_of(align) - base();
+        let Some(end) = start.checked_add(size).filter(|&end| end <= ARENA_SIZE) else {
+            return std::ptr::null_mut();
+        };
+        match OFFSET.compare_exchange_weak(offset, end, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => unsafe {
+                let ptr = (base() + start) as *mut u8;
+                (ptr.sub(HEADER_SIZE) as *mut usize).write(size);
+                return ptr as *mut c_void;
+            },
+            Err(current) => offset = current,
+        }
+    }
+}
+
+/// 引导区中一个块的大小
+///
+/// 调用者需保证`ptr`是`alloc`返回的指针。
+pub(crate) unsafe fn size_of(ptr: *const c_void) -> usize {
+    ((ptr as *const u8).sub(HEADER_SIZE) as *const usize).read()
+}
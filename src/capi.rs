@@ -0,0 +1,109 @@
+//! 供被测程序调用的C接口
+//!
+//! 声明见`include/memleak.h`。被测程序应通过`dlsym`查找这些函数，
+//! 未预加载本库时查找失败即可跳过，无需链接本库。
+
+use crate::{
+    dump, enter_critical_section, exit_critical_section, registry, snapshot, GLOBAL_TRACKER,
+    IGNORE_DEPTH, TRACKING_ENABLED,
+};
+use libc::{c_char, c_int};
+use std::ffi::CStr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+/// 在hook临界区内执行：期间的分配属于本库自身，不进入存活分配表
+fn untracked<R>(f: impl FnOnce() -> R) -> R {
+    let entered = enter_critical_section();
+    let result = f();
+    if entered {
+        exit_critical_section();
+    }
+    result
+}
+
+/// 把可空的C字符串转换为路径，空指针返回None
+unsafe fn optional_path<'a>(path: *const c_char) -> Option<&'a Path> {
+    if path.is_null() {
+        return None;
+    }
+    let path = CStr::from_ptr(path).to_str().ok()?;
+    Some(Path::new(path))
+}
+
+/// 恢复记录新的分配
+#[no_mangle]
+pub extern "C" fn memleak_enable() {
+    TRACKING_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// 暂停记录新的分配，已记录的块仍正常匹配释放
+#[no_mangle]
+pub extern "C" fn memleak_disable() {
+    TRACKING_ENABLED.store(false, Ordering::Relaxed);
+    registry::invalidate_freed();
+}
+
+/// 拍摄一次堆快照，返回快照编号（从1开始）
+#[no_mangle]
+pub extern "C" fn memleak_snapshot() -> u32 {
+    untracked(snapshot::take)
+}
+
+/// 把当前存活分配表写到文件，`path`为空时使用带时间戳的默认文件名
+///
+/// 成功返回0，失败返回-1。
+#[no_mangle]
+pub unsafe extern "C" fn memleak_dump(path: *const c_char) -> c_int {
+    untracked(|| match dump::write_dump(optional_path(path)) {
+        Ok(path) => {
+            eprintln!("[memleak] Heap snapshot written to {}", path.display());
+            0
+        }
+        Err(err) => {
+            eprintln!("[memleak] Failed to write heap snapshot: {err}");
+            -1
+        }
+    })
+}
+
+/// 把快照`from`到`to`的对比写到文件，`path`为空时使用带时间戳的默认文件名
+///
+/// 成功返回0，快照不存在或写入失败返回-1。
+#[no_mangle]
+pub unsafe extern "C" fn memleak_diff(from: u32, to: u32, path: *const c_char) -> c_int {
+    untracked(
+        || match snapshot::write_diff(from, to, optional_path(path)) {
+            Ok(path) => {
+                eprintln!("[memleak] Heap diff written to {}", path.display());
+                0
+            }
+            Err(err) => {
+                eprintln!("[memleak] Failed to write heap diff: {err}");
+                -1
+            }
+        },
+    )
+}
+
+/// 向Tracy发送一条消息
+#[no_mangle]
+pub unsafe extern "C" fn memleak_message(message: *const c_char) {
+    if message.is_null() {
+        return;
+    }
+    let message = CStr::from_ptr(message);
+    untracked(|| GLOBAL_TRACKER.message(&message.to_string_lossy()));
+}
+
+/// 开始忽略当前线程的分配，可以嵌套
+#[no_mangle]
+pub extern "C" fn memleak_ignore_begin() {
+    IGNORE_DEPTH.with(|depth| depth.set(depth.get() + 1));
+}
+
+/// 结束一层`memleak_ignore_begin`
+#[no_mangle]
+pub extern "C" fn memleak_ignore_end() {
+    IGNORE_DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+}
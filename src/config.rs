@@ -0,0 +1,660 @@
+//! 运行时配置
+//!
+//! 所有配置都通过环境变量传入，在库加载时由`init`读取一次。
+//! hook在`init`之前就可能被调用，因此每个配置项都带有默认值。
+
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Tracy支持的最大调用栈深度
+const MAX_CALLSTACK_DEPTH: i32 = 62;
+
+/// 默认调用栈深度
+const DEFAULT_CALLSTACK_DEPTH: i32 = 5;
+
+/// 采集调用栈的深度，0表示不采集调用栈
+static CALLSTACK_DEPTH: AtomicI32 = AtomicI32::new(DEFAULT_CALLSTACK_DEPTH);
+
+/// 当前配置的调用栈深度
+#[inline]
+pub(crate) fn callstack_depth() -> i32 {
+    CALLSTACK_DEPTH.load(Ordering::Relaxed)
+}
+
+/// 追踪的最小请求大小（含）
+static MIN_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// 追踪的最大请求大小（含）
+static MAX_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// 请求大小是否在追踪范围内，默认追踪所有大小
+#[inline]
+pub(crate) fn size_in_range(size: usize) -> bool {
+    MIN_SIZE.load(Ordering::Relaxed) <= size && size <= MAX_SIZE.load(Ordering::Relaxed)
+}
+
+/// 采样间隔（字节），0表示不采样、记录每一次分配
+static SAMPLE_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+
+/// 平均每多少字节采样一次分配，0表示不采样
+#[inline]
+pub(crate) fn sample_interval() -> usize {
+    SAMPLE_INTERVAL.load(Ordering::Relaxed)
+}
+
+/// 退出时泄漏报告的输出位置
+pub(crate) enum ReportTarget {
+    /// 不输出报告
+    Disabled,
+    /// 输出到标准错误
+    Stderr,
+    /// 输出到文件，路径中的`%p`会替换为进程号
+    File(PathBuf),
+}
+
+static REPORT_TARGET: OnceLock<ReportTarget> = OnceLock::new();
+
+/// 泄漏报告的输出位置，默认输出到标准错误
+pub(crate) fn report_target() -> &'static ReportTarget {
+    REPORT_TARGET.get_or_init(|| ReportTarget::Stderr)
+}
+
+/// 分配和释放时是否采集本库自己的调用栈
+static COLLECT_STACKS: AtomicBool = AtomicBool::new(true);
+
+/// 是否采集本库自己的调用栈
+///
+/// Tracy上报时自己采集调用栈，本库的调用栈只用于泄漏报告、堆快照和内存错误报告。
+/// 这些输出都关闭时不再采集，分配记录中的调用栈为空。
+#[inline]
+pub(crate) fn collect_stacks() -> bool {
+    COLLECT_STACKS.load(Ordering::Relaxed)
+}
+
+/// 报告前是否做可达性扫描
+static REACHABILITY_SCAN: AtomicBool = AtomicBool::new(true);
+
+/// 报告前是否做可达性扫描，默认开启
+pub(crate) fn reachability_scan() -> bool {
+    REACHABILITY_SCAN.load(Ordering::Relaxed)
+}
+
+static SUPPRESSIONS_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// 泄漏屏蔽规则文件路径
+pub(crate) fn suppressions_path() -> Option<&'static PathBuf> {
+    SUPPRESSIONS_PATH.get_or_init(|| None).as_ref()
+}
+
+/// 触发堆快照导出的信号，0表示未开启
+static DUMP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// 触发堆快照导出的信号，未配置时返回None
+pub(crate) fn dump_signal() -> Option<i32> {
+    match DUMP_SIGNAL.load(Ordering::Relaxed) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+static DUMP_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// 堆快照文件的输出目录，未配置时写到当前目录
+pub(crate) fn dump_dir() -> Option<&'static PathBuf> {
+    DUMP_DIR.get_or_init(|| None).as_ref()
+}
+
+/// 默认保留的释放历史条数
+const DEFAULT_FREE_HISTORY: usize = 16384;
+
+/// 保留的释放历史条数，0表示不检测重复释放
+static FREE_HISTORY: AtomicUsize = AtomicUsize::new(DEFAULT_FREE_HISTORY);
+
+/// 保留多少个最近释放的块用于识别重复释放
+#[inline]
+pub(crate) fn free_history() -> usize {
+    FREE_HISTORY.load(Ordering::Relaxed)
+}
+
+/// 释放历史是否记录释放时的调用栈
+///
+/// 每次释放都要采集调用栈，开销与分配时相当，默认只记录地址和分配时的信息。
+static FREE_STACKS: AtomicBool = AtomicBool::new(false);
+
+/// 释放历史是否记录释放时的调用栈
+#[inline]
+pub(crate) fn free_stacks() -> bool {
+    FREE_STACKS.load(Ordering::Relaxed)
+}
+
+/// 隔离区的字节预算，0表示不开启隔离区
+static QUARANTINE_BUDGET: AtomicUsize = AtomicUsize::new(0);
+
+/// 隔离区最多保留多少字节的已释放块
+#[inline]
+pub(crate) fn quarantine_budget() -> usize {
+    QUARANTINE_BUDGET.load(Ordering::Relaxed)
+}
+
+/// 红区的字节数，0表示不开启红区
+static REDZONE: AtomicUsize = AtomicUsize::new(0);
+
+/// 带红区的块前后各有多少字节红区，总是分配器最小对齐的整数倍
+#[inline]
+pub(crate) fn redzone() -> usize {
+    REDZONE.load(Ordering::Relaxed)
+}
+
+/// 是否开启保护页模式
+static GUARD_PAGES: AtomicBool = AtomicBool::new(false);
+
+/// 放到保护页上的最小请求大小（含）
+static GUARD_MIN_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// 放到保护页上的最大请求大小（含）
+static GUARD_MAX_SIZE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// 平均每多少个大小符合的分配放一个到保护页上
+static GUARD_RATE: AtomicUsize = AtomicUsize::new(1);
+
+/// 默认保持不可访问的已释放保护页块数
+const DEFAULT_GUARD_DELAY: usize = 1024;
+
+/// 保持不可访问的已释放保护页块数
+static GUARD_DELAY: AtomicUsize = AtomicUsize::new(DEFAULT_GUARD_DELAY);
+
+/// 是否开启保护页模式
+#[inline]
+pub(crate) fn guard_pages() -> bool {
+    GUARD_PAGES.load(Ordering::Relaxed)
+}
+
+/// 请求大小是否在保护页模式的范围内
+#[inline]
+pub(crate) fn guard_size_in_range(size: usize) -> bool {
+    GUARD_MIN_SIZE.load(Ordering::Relaxed) <= size && size <= GUARD_MAX_SIZE.load(Ordering::Relaxed)
+}
+
+/// 大小符合的分配中平均每多少个放一个到保护页上，1表示全部
+#[inline]
+pub(crate) fn guard_rate() -> usize {
+    GUARD_RATE.load(Ordering::Relaxed)
+}
+
+/// 释放后继续保持不可访问的保护页块数
+pub(crate) fn guard_delay() -> usize {
+    GUARD_DELAY.load(Ordering::Relaxed)
+}
+
+/// 检测到重复释放等错误时是否中止进程
+static ABORT_ON_ERROR: AtomicBool = AtomicBool::new(false);
+
+/// 检测到错误后是否中止进程
+pub(crate) fn abort_on_error() -> bool {
+    ABORT_ON_ERROR.load(Ordering::Relaxed)
+}
+
+/// 启动时是否开启追踪，默认开启
+static START_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// 启动时是否开启追踪
+pub(crate) fn start_enabled() -> bool {
+    START_ENABLED.load(Ordering::Relaxed)
+}
+
+static TOGGLE_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// 切换追踪开关的信号，未配置时返回None
+pub(crate) fn toggle_signal() -> Option<i32> {
+    match TOGGLE_SIGNAL.load(Ordering::Relaxed) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+static SCAN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// 可达性扫描暂停其它线程使用的信号，未配置时返回None，由扫描自己挑选
+pub(crate) fn scan_signal() -> Option<i32> {
+    match SCAN_SIGNAL.load(Ordering::Relaxed) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+static CONTROL_FILE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// 控制追踪开关的文件，未配置时返回None
+pub(crate) fn control_file() -> Option<&'static PathBuf> {
+    CONTROL_FILE.get_or_init(|| None).as_ref()
+}
+
+/// 解析信号名或信号编号，接受`USR2`、`SIGUSR2`、`12`等写法
+fn parse_signal(value: &str) -> Option<i32> {
+    let value = value.trim();
+    if let Ok(number) = value.parse::<i32>() {
+        return (1..=libc::SIGRTMAX()).contains(&number).then_some(number);
+    }
+    let name = value.strip_prefix("SIG").unwrap_or(value);
+    let signal = match name.to_ascii_uppercase().as_str() {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "PROF" => libc::SIGPROF,
+        "URG" => libc::SIGURG,
+        "WINCH" => libc::SIGWINCH,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+/// fork后子进程中的追踪策略
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ForkPolicy {
+    /// 清空继承的记录，在子进程中重新开始追踪
+    Fresh,
+    /// 子进程中关闭所有hook
+    Disable,
+}
+
+static FORK_POLICY: OnceLock<ForkPolicy> = OnceLock::new();
+
+/// fork后子进程中的追踪策略，默认重新开始追踪
+pub(crate) fn fork_policy() -> ForkPolicy {
+    *FORK_POLICY.get_or_init(|| ForkPolicy::Fresh)
+}
+
+/// 是否从子进程的`LD_PRELOAD`中移除本库
+static STRIP_PRELOAD: AtomicBool = AtomicBool::new(false);
+
+/// 是否从子进程的`LD_PRELOAD`中移除本库，默认不移除
+pub(crate) fn strip_preload() -> bool {
+    STRIP_PRELOAD.load(Ordering::Relaxed)
+}
+
+static PRELOAD_ALLOWLIST: OnceLock<Vec<String>> = OnceLock::new();
+
+/// 移除预加载时仍保留本库的程序名模式
+pub(crate) fn preload_allowlist() -> &'static [String] {
+    PRELOAD_ALLOWLIST.get_or_init(Vec::new)
+}
+
+/// 分配事件上报到Tracy的哪个内存池
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PoolMode {
+    /// 全部上报到默认内存池
+    Single = 0,
+    /// 按分配线程的线程名分池
+    Thread = 1,
+    /// 按请求大小所在的区间分池
+    SizeClass = 2,
+}
+
+/// 内存池划分方式：hook在`init`之前就会读取，因此不能用只能设置一次的`OnceLock`
+static POOL_MODE: AtomicU8 = AtomicU8::new(PoolMode::Single as u8);
+
+/// 内存池划分方式，默认不分池
+#[inline]
+pub(crate) fn pool_mode() -> PoolMode {
+    match POOL_MODE.load(Ordering::Relaxed) {
+        1 => PoolMode::Thread,
+        2 => PoolMode::SizeClass,
+        _ => PoolMode::Single,
+    }
+}
+
+/// 默认的大小区间上界
+const DEFAULT_SIZE_CLASSES: [usize; 3] = [64, 1024, 64 * 1024];
+
+/// 按大小分池时的各个区间
+pub(crate) struct SizeClasses {
+    /// 各区间的上界（含），升序
+    bounds: Vec<usize>,
+    /// 各区间的内存池名，比上界多一个（超过最大上界的区间）
+    names: Vec<&'static CStr>,
+}
+
+impl SizeClasses {
+    fn new(bounds: Vec<usize>) -> Self {
+        let mut names: Vec<String> = bounds
+            .iter()
+            .map(|&bound| format!("≤{}", format_size(bound)))
+            .collect();
+        names.push(format!(">{}", format_size(*bounds.last().unwrap())));
+        // Tracy按指针区分内存池，名字需要一直存活
+        let names = names
+            .into_iter()
+            .map(|name| &*Box::leak(CString::new(name).unwrap().into_boxed_c_str()))
+            .collect();
+        SizeClasses { bounds, names }
+    }
+
+    /// 请求大小所在区间的内存池名
+    pub(crate) fn pool(&self, size: usize) -> &'static CStr {
+        let index = self.bounds.partition_point(|&bound| bound < size);
+        self.names[index]
+    }
+}
+
+static SIZE_CLASSES: OnceLock<SizeClasses> = OnceLock::new();
+
+/// 按大小分池时的各个区间
+///
+/// 只在按大小分池时读取，此时`load`已经设置好区间。
+pub(crate) fn size_classes() -> &'static SizeClasses {
+    SIZE_CLASSES.get_or_init(|| SizeClasses::new(DEFAULT_SIZE_CLASSES.to_vec()))
+}
+
+/// 以最大的整除单位显示字节数
+fn format_size(size: usize) -> String {
+    const UNITS: [(usize, &str); 3] = [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")];
+    UNITS
+        .iter()
+        .find(|&&(unit, _)| size >= unit && size.is_multiple_of(unit))
+        .map_or_else(
+            || format!("{size}B"),
+            |&(unit, name)| format!("{}{name}", size / unit),
+        )
+}
+
+/// 解析字节数，支持`K`/`M`/`G`后缀（以1024为单位）
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (number, shift) = match value.char_indices().last()? {
+        (index, 'k' | 'K') => (&value[..index], 10),
+        (index, 'm' | 'M') => (&value[..index], 20),
+        (index, 'g' | 'G') => (&value[..index], 30),
+        _ => (value, 0),
+    };
+    number.trim().parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+/// 解析大小范围：`<min>-<max>`，两端都可以省略；只有一个大小时表示恰好这个大小
+fn parse_size_range(value: &str) -> Option<(usize, usize)> {
+    let Some((min, max)) = value.split_once('-') else {
+        let size = parse_size(value)?;
+        return Some((size, size));
+    };
+    let min = match min.trim() {
+        "" => 0,
+        min => parse_size(min)?,
+    };
+    let max = match max.trim() {
+        "" => usize::MAX,
+        max => parse_size(max)?,
+    };
+    (min <= max).then_some((min, max))
+}
+
+/// 解析逗号分隔的区间上界，结果升序且不重复
+fn parse_size_classes(value: &str) -> Option<Vec<usize>> {
+    let mut bounds: Vec<usize> = value.split(',').map(parse_size).collect::<Option<_>>()?;
+    bounds.sort_unstable();
+    bounds.dedup();
+    Some(bounds)
+}
+
+/// 默认的Tracy曲线发布间隔（毫秒）
+const DEFAULT_PLOT_INTERVAL_MS: u64 = 500;
+
+/// Tracy曲线的发布间隔（毫秒），0表示不发布
+static PLOT_INTERVAL_MS: AtomicU64 = AtomicU64::new(DEFAULT_PLOT_INTERVAL_MS);
+
+/// Tracy曲线的发布间隔，为0时不发布
+pub(crate) fn plot_interval() -> Duration {
+    Duration::from_millis(PLOT_INTERVAL_MS.load(Ordering::Relaxed))
+}
+
+/// 读取环境变量，未设置或为空时返回None
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// 从环境变量加载配置
+pub(crate) fn load() {
+    if let Some(value) = env_var("MEMLEAK_CALLSTACK_DEPTH") {
+        match value.trim().parse::<i32>() {
+            Ok(depth) if depth >= 0 => {
+                CALLSTACK_DEPTH.store(depth.min(MAX_CALLSTACK_DEPTH), Ordering::Relaxed);
+            }
+            _ => eprintln!("[memleak] Invalid MEMLEAK_CALLSTACK_DEPTH: {value}"),
+        }
+    }
+
+    for (name, limit) in [
+        ("MEMLEAK_MIN_SIZE", &MIN_SIZE),
+        ("MEMLEAK_MAX_SIZE", &MAX_SIZE),
+        ("MEMLEAK_SAMPLE_INTERVAL", &SAMPLE_INTERVAL),
+        ("MEMLEAK_QUARANTINE", &QUARANTINE_BUDGET),
+    ] {
+        if let Some(value) = env_var(name) {
+            match parse_size(&value) {
+                Some(size) => limit.store(size, Ordering::Relaxed),
+                None => eprintln!("[memleak] Invalid {name}: {value}"),
+            }
+        }
+    }
+    if let Some(value) = env_var("MEMLEAK_REDZONE") {
+        // 红区向上取整到分配器的最小对齐，返回的内部指针才能保持原有的对齐
+        let alignment = 2 * std::mem::size_of::<usize>();
+        match parse_size(&value).and_then(|size| size.checked_next_multiple_of(alignment)) {
+            Some(size) => REDZONE.store(size, Ordering::Relaxed),
+            None => eprintln!("[memleak] Invalid MEMLEAK_REDZONE: {value}"),
+        }
+    }
+
+    if let Some(value) = env_var("MEMLEAK_GUARD_SIZE") {
+        match parse_size_range(&value) {
+            Some((min, max)) => {
+                GUARD_MIN_SIZE.store(min, Ordering::Relaxed);
+                GUARD_MAX_SIZE.store(max, Ordering::Relaxed);
+                GUARD_PAGES.store(true, Ordering::Relaxed);
+            }
+            None => eprintln!("[memleak] Invalid MEMLEAK_GUARD_SIZE: {value}"),
+        }
+    }
+    if let Some(value) = env_var("MEMLEAK_GUARD_RATE") {
+        match value.trim().parse::<usize>() {
+            Ok(rate) if rate > 0 => {
+                GUARD_RATE.store(rate, Ordering::Relaxed);
+                GUARD_PAGES.store(true, Ordering::Relaxed);
+            }
+            _ => eprintln!("[memleak] Invalid MEMLEAK_GUARD_RATE: {value}"),
+        }
+    }
+    if let Some(value) = env_var("MEMLEAK_GUARD_DELAY") {
+        match value.trim().parse::<usize>() {
+            Ok(count) => GUARD_DELAY.store(count, Ordering::Relaxed),
+            Err(_) => eprintln!("[memleak] Invalid MEMLEAK_GUARD_DELAY: {value}"),
+        }
+    }
+
+    let report = match env_var("MEMLEAK_REPORT") {
+        None => ReportTarget::Stderr,
+        Some(value) => match value.as_str() {
+            "0" | "off" | "none" => ReportTarget::Disabled,
+            "stderr" | "-" => ReportTarget::Stderr,
+            path => ReportTarget::File(PathBuf::from(path)),
+        },
+    };
+    let _ = REPORT_TARGET.set(report);
+    if let Some(value) = env_var("MEMLEAK_REACHABILITY") {
+        REACHABILITY_SCAN.store(!matches!(value.as_str(), "0" | "off"), Ordering::Relaxed);
+    }
+    let _ = SUPPRESSIONS_PATH.set(env_var("MEMLEAK_SUPPRESSIONS").map(PathBuf::from));
+    if let Some(value) = env_var("MEMLEAK_DUMP_SIGNAL") {
+        match parse_signal(&value) {
+            Some(signal) => DUMP_SIGNAL.store(signal, Ordering::Relaxed),
+            None => eprintln!("[memleak] Invalid MEMLEAK_DUMP_SIGNAL: {value}"),
+        }
+    }
+    let _ = DUMP_DIR.set(env_var("MEMLEAK_DUMP_DIR").map(PathBuf::from));
+    if let Some(value) = env_var("MEMLEAK_ENABLED") {
+        START_ENABLED.store(!matches!(value.as_str(), "0" | "off"), Ordering::Relaxed);
+    }
+    if let Some(value) = env_var("MEMLEAK_TOGGLE_SIGNAL") {
+        match parse_signal(&value) {
+            Some(signal) if Some(signal) == dump_signal() => {
+                eprintln!(
+                    "[memleak] MEMLEAK_TOGGLE_SIGNAL conflicts with MEMLEAK_DUMP_SIGNAL: {value}"
+                )
+            }
+            Some(signal) => TOGGLE_SIGNAL.store(signal, Ordering::Relaxed),
+            None => eprintln!("[memleak] Invalid MEMLEAK_TOGGLE_SIGNAL: {value}"),
+        }
+    }
+    if let Some(value) = env_var("MEMLEAK_SCAN_SIGNAL") {
+        match parse_signal(&value) {
+            Some(signal) if Some(signal) == dump_signal() || Some(signal) == toggle_signal() => {
+                eprintln!("[memleak] MEMLEAK_SCAN_SIGNAL conflicts with another signal: {value}")
+            }
+            Some(signal) => SCAN_SIGNAL.store(signal, Ordering::Relaxed),
+            None => eprintln!("[memleak] Invalid MEMLEAK_SCAN_SIGNAL: {value}"),
+        }
+    }
+    let _ = CONTROL_FILE.set(env_var("MEMLEAK_CONTROL_FILE").map(PathBuf::from));
+    if let Some(value) = env_var("MEMLEAK_FREE_HISTORY") {
+        match value.trim().parse::<usize>() {
+            Ok(count) => FREE_HISTORY.store(count, Ordering::Relaxed),
+            Err(_) => eprintln!("[memleak] Invalid MEMLEAK_FREE_HISTORY: {value}"),
+        }
+    }
+    if let Some(value) = env_var("MEMLEAK_FREE_STACKS") {
+        FREE_STACKS.store(!matches!(value.as_str(), "0" | "off"), Ordering::Relaxed);
+    }
+    if let Some(value) = env_var("MEMLEAK_ABORT_ON_ERROR") {
+        ABORT_ON_ERROR.store(!matches!(value.as_str(), "0" | "off"), Ordering::Relaxed);
+    }
+    if let Some(value) = env_var("MEMLEAK_STRIP_PRELOAD") {
+        STRIP_PRELOAD.store(!matches!(value.as_str(), "0" | "off"), Ordering::Relaxed);
+    }
+    let allowlist = env_var("MEMLEAK_PRELOAD_ALLOW")
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    let _ = PRELOAD_ALLOWLIST.set(allowlist);
+    // 区间要在内存池划分方式生效之前设置好：按大小分池之后hook才会读取区间
+    let mut bounds = DEFAULT_SIZE_CLASSES.to_vec();
+    if let Some(value) = env_var("MEMLEAK_SIZE_CLASSES") {
+        match parse_size_classes(&value) {
+            Some(parsed) => bounds = parsed,
+            None => eprintln!("[memleak] Invalid MEMLEAK_SIZE_CLASSES: {value}"),
+        }
+    }
+    if SIZE_CLASSES.set(SizeClasses::new(bounds)).is_err() {
+        eprintln!(
+            "[memleak] Size classes were used before initialization, ignoring MEMLEAK_SIZE_CLASSES"
+        );
+    }
+    if let Some(value) = env_var("MEMLEAK_POOL") {
+        let mode = match value.as_str() {
+            "single" => Some(PoolMode::Single),
+            "thread" => Some(PoolMode::Thread),
+            "size" => Some(PoolMode::SizeClass),
+            _ => None,
+        };
+        match mode {
+            Some(mode) => POOL_MODE.store(mode as u8, Ordering::Relaxed),
+            None => eprintln!("[memleak] Invalid MEMLEAK_POOL: {value}"),
+        }
+    }
+    if let Some(value) = env_var("MEMLEAK_PLOT_INTERVAL") {
+        match value.trim().parse::<u64>() {
+            Ok(interval) => PLOT_INTERVAL_MS.store(interval, Ordering::Relaxed),
+            Err(_) => eprintln!("[memleak] Invalid MEMLEAK_PLOT_INTERVAL: {value}"),
+        }
+    }
+    let stacks_used = !matches!(report_target(), ReportTarget::Disabled)
+        || dump_signal().is_some()
+        || free_stacks()
+        || quarantine_budget() > 0
+        || redzone() > 0
+        || guard_pages();
+    COLLECT_STACKS.store(stacks_used, Ordering::Relaxed);
+    if let Some(value) = env_var("MEMLEAK_FOLLOW_FORK") {
+        match value.as_str() {
+            "1" | "on" | "fresh" => {
+                let _ = FORK_POLICY.set(ForkPolicy::Fresh);
+            }
+            "0" | "off" | "disable" => {
+                let _ = FORK_POLICY.set(ForkPolicy::Disable);
+            }
+            _ => eprintln!("[memleak] Invalid MEMLEAK_FOLLOW_FORK: {value}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_size, parse_size, parse_size_classes, parse_size_range, SizeClasses};
+
+    #[test]
+    fn sizes_with_suffixes() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size(" 64 "), Some(64));
+        assert_eq!(parse_size("4k"), Some(4096));
+        assert_eq!(parse_size("4 K"), Some(4096));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("1T"), None);
+        assert_eq!(parse_size(&format!("{}G", usize::MAX)), None);
+    }
+
+    #[test]
+    fn size_ranges() {
+        assert_eq!(parse_size_range("64"), Some((64, 64)));
+        assert_eq!(parse_size_range("16-1K"), Some((16, 1024)));
+        assert_eq!(parse_size_range("4K-"), Some((4096, usize::MAX)));
+        assert_eq!(parse_size_range("-256"), Some((0, 256)));
+        assert_eq!(parse_size_range(" 1 - 2 "), Some((1, 2)));
+        assert_eq!(parse_size_range("-"), Some((0, usize::MAX)));
+        assert_eq!(parse_size_range("2K-1K"), None);
+        assert_eq!(parse_size_range("1-x"), None);
+        assert_eq!(parse_size_range(""), None);
+    }
+
+    #[test]
+    fn formatted_sizes() {
+        assert_eq!(format_size(0), "0B");
+        assert_eq!(format_size(64), "64B");
+        assert_eq!(format_size(1024), "1KiB");
+        assert_eq!(format_size(1536), "1536B");
+        assert_eq!(format_size(64 << 10), "64KiB");
+        assert_eq!(format_size(3 << 20), "3MiB");
+        assert_eq!(format_size(1 << 30), "1GiB");
+    }
+
+    #[test]
+    fn size_class_list() {
+        assert_eq!(parse_size_classes("1K,64,1K,16"), Some(vec![16, 64, 1024]));
+        assert_eq!(parse_size_classes("64,x"), None);
+        assert_eq!(parse_size_classes(""), None);
+    }
+
+    #[test]
+    fn size_class_boundaries() {
+        let classes = SizeClasses::new(vec![64, 1024]);
+        let pool = |size| classes.pool(size).to_str().unwrap();
+        assert_eq!(pool(0), "≤64B");
+        assert_eq!(pool(64), "≤64B");
+        assert_eq!(pool(65), "≤1KiB");
+        assert_eq!(pool(1024), "≤1KiB");
+        assert_eq!(pool(1025), ">1KiB");
+        assert_eq!(pool(usize::MAX), ">1KiB");
+    }
+}
@@ -0,0 +1,103 @@
+//! 运行时开关追踪
+//!
+//! 除了C接口`memleak_enable`/`memleak_disable`，还可以通过信号或控制文件切换追踪状态，
+//! 让本库常驻预加载、只在排查问题时才付出追踪的开销。关闭期间的新分配不进入存活分配表，
+//! 它们的释放在表中查不到，不会作为不匹配的释放上报给Tracy；关闭前已记录的块仍正常匹配释放。
+
+use crate::{config, enter_critical_section, registry, TRACKING_ENABLED};
+use libc::c_int;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// 控制文件的轮询间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// 切换信号处理函数：翻转开关并向标准错误写一行提示，满足异步信号安全的要求
+extern "C" fn toggle_signal_handler(_sig: c_int) {
+    let enabled = !TRACKING_ENABLED.fetch_xor(true, Ordering::Relaxed);
+    let message: &[u8] = if enabled {
+        b"[memleak] Tracking enabled\n"
+    } else {
+        registry::invalidate_freed();
+        b"[memleak] Tracking disabled\n"
+    };
+    unsafe {
+        let errno = *libc::__errno_location();
+        libc::write(
+            libc::STDERR_FILENO,
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+        );
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// 解析控制文件的内容
+fn parse_state(content: &str) -> Option<bool> {
+    match content.trim() {
+        "1" | "on" | "enable" => Some(true),
+        "0" | "off" | "disable" => Some(false),
+        _ => None,
+    }
+}
+
+/// 控制文件轮询线程：文件内容变化时按内容设置开关
+///
+/// 只在内容变化时生效，期间通过信号或C接口做的切换不会被覆盖。
+fn watcher(path: &Path) {
+    // 本线程的分配属于本库自身，始终不追踪
+    enter_critical_section();
+    let mut last: Option<String> = None;
+    loop {
+        let content = std::fs::read_to_string(path).ok();
+        if content != last {
+            if let Some(content) = &content {
+                match parse_state(content) {
+                    Some(enabled) => {
+                        TRACKING_ENABLED.store(enabled, Ordering::Relaxed);
+                        if !enabled {
+                            registry::invalidate_freed();
+                        }
+                        let state = if enabled { "enabled" } else { "disabled" };
+                        eprintln!("[memleak] Tracking {state} by {}", path.display());
+                    }
+                    None => eprintln!("[memleak] Invalid control file content: {}", content.trim()),
+                }
+            }
+            last = content;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// 启动控制文件轮询线程，未配置控制文件时不启动
+///
+/// fork后的子进程中没有轮询线程，需要重新调用。
+pub(crate) fn start_watcher() {
+    let Some(path) = config::control_file() else {
+        return;
+    };
+    let path: PathBuf = path.clone();
+    let spawned = std::thread::Builder::new()
+        .name("memleak-control".into())
+        .spawn(move || watcher(&path));
+    if let Err(err) = spawned {
+        eprintln!("[memleak] Failed to start control thread: {err}");
+    }
+}
+
+/// 设置初始状态，安装切换信号处理函数并启动控制文件轮询线程
+pub(crate) fn install() {
+    TRACKING_ENABLED.store(config::start_enabled(), Ordering::Relaxed);
+    if let Some(signal) = config::toggle_signal() {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = toggle_signal_handler as *const () as usize;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut());
+        }
+    }
+    start_watcher();
+}
@@ -0,0 +1,309 @@
+//! C++ operator new/delete拦截
+//!
+//! 按Itanium C++ ABI的修饰名拦截libstdc++/libc++导出的各个operator new/delete重载，
+//! 包括带大小、带对齐和nothrow的版本（`size_t`按LP64平台修饰为`m`）。块记录由哪一族函数
+//! 分配，释放时族不一致（malloc的块被delete、new的块被free、new[]的块被delete等）
+//! 就连同分配和释放的调用栈一起报告，块仍照常释放：这些运算符本身就是malloc/free的包装。
+//! 程序自己定义了这些运算符时，它的定义优先于本库，不会经过这里。
+//!
+//! 分配失败时离开临界区调用`new_handler`后重试，重试得到的块同样记录；没有`new_handler`时
+//! 交给原始的operator new抛出`std::bad_alloc`，因此会抛出异常的版本使用`C-unwind`调用约定，
+//! 让C++异常可以穿过hook。
+//! 原始函数只在需要时解析，不链接C++运行库的程序不受影响。
+
+use crate::registry::Family;
+use crate::{
+    alloc_block, deallocate, enter_critical_section, exit_critical_section, track_alloc,
+    untracked_alloc, Original, ORIGINAL_MEMALIGN,
+};
+use libc::{c_void, size_t};
+use std::ffi::CStr;
+
+/// Tracy中operator new内存池的名称
+pub(crate) const NEW_POOL: &CStr = c"new";
+/// Tracy中operator new[]内存池的名称
+pub(crate) const NEW_ARRAY_POOL: &CStr = c"new[]";
+
+/// 原始operator new函数指针类型
+type NewFn = unsafe extern "C-unwind" fn(size: size_t) -> *mut c_void;
+/// 原始nothrow operator new函数指针类型
+type NewNothrowFn = unsafe extern "C" fn(size: size_t, tag: *const c_void) -> *mut c_void;
+/// 原始带对齐的operator new函数指针类型
+type NewAlignedFn = unsafe extern "C-unwind" fn(size: size_t, align: size_t) -> *mut c_void;
+/// `std::new_handler`类型
+type NewHandlerFn = unsafe extern "C-unwind" fn();
+/// 原始std::get_new_handler函数指针类型
+type GetNewHandlerFn = unsafe extern "C" fn() -> Option<NewHandlerFn>;
+/// 原始带对齐的nothrow operator new函数指针类型
+type NewAlignedNothrowFn =
+    unsafe extern "C" fn(size: size_t, align: size_t, tag: *const c_void) -> *mut c_void;
+
+/// 原始std::get_new_handler()
+static ORIGINAL_GET_NEW_HANDLER: Original<GetNewHandlerFn> =
+    Original::new(c"_ZSt15get_new_handlerv");
+/// 原始operator new(size_t)
+static ORIGINAL_NEW: Original<NewFn> = Original::new(c"_Znwm");
+/// 原始operator new[](size_t)
+static ORIGINAL_NEW_ARRAY: Original<NewFn> = Original::new(c"_Znam");
+/// 原始operator new(size_t, const std::nothrow_t&)
+static ORIGINAL_NEW_NOTHROW: Original<NewNothrowFn> = Original::new(c"_ZnwmRKSt9nothrow_t");
+/// 原始operator new[](size_t, const std::nothrow_t&)
+static ORIGINAL_NEW_ARRAY_NOTHROW: Original<NewNothrowFn> = Original::new(c"_ZnamRKSt9nothrow_t");
+/// 原始operator new(size_t, std::align_val_t)
+static ORIGINAL_NEW_ALIGNED: Original<NewAlignedFn> = Original::new(c"_ZnwmSt11align_val_t");
+/// 原始operator new[](size_t, std::align_val_t)
+static ORIGINAL_NEW_ARRAY_ALIGNED: Original<NewAlignedFn> = Original::new(c"_ZnamSt11align_val_t");
+/// 原始operator new(size_t, std::align_val_t, const std::nothrow_t&)
+static ORIGINAL_NEW_ALIGNED_NOTHROW: Original<NewAlignedNothrowFn> =
+    Original::new(c"_ZnwmSt11align_val_tRKSt9nothrow_t");
+/// 原始operator new[](size_t, std::align_val_t, const std::nothrow_t&)
+static ORIGINAL_NEW_ARRAY_ALIGNED_NOTHROW: Original<NewAlignedNothrowFn> =
+    Original::new(c"_ZnamSt11align_val_tRKSt9nothrow_t");
+
+/// 临界区守卫：离开作用域时退出临界区，C++异常穿过hook时同样会退出
+struct Section;
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        exit_critical_section();
+    }
+}
+
+/// 在临界区内分配并记录一个块
+///
+/// `align`为0表示默认对齐，带对齐的块不加红区也不放到保护页上，与`memalign`相同。
+#[inline]
+unsafe fn new_block(size: usize, align: usize, family: Family) -> *mut c_void {
+    let _section = Section;
+    if align == 0 {
+        return alloc_block(size, family);
+    }
+    let ptr = ORIGINAL_MEMALIGN.get()(align, size);
+    if !ptr.is_null() {
+        track_alloc(ptr, size, family);
+    }
+    ptr
+}
+
+/// 会抛出异常的operator new的共同实现
+#[inline]
+unsafe fn operator_new(
+    size: usize,
+    align: usize,
+    family: Family,
+    original: impl Fn() -> *mut c_void,
+) -> *mut c_void {
+    if !enter_critical_section() {
+        return untracked_alloc(original());
+    }
+    let ptr = new_block(size, align, family);
+    if !ptr.is_null() {
+        return ptr;
+    }
+    retry_new(size, align, family, original)
+}
+
+/// 分配失败后的重试循环
+///
+/// `new_handler`在临界区外调用，它释放内存时照常经过hook；之后的重试同样记录。
+/// 没有`new_handler`时交给`original`抛出`std::bad_alloc`，它内部的分配不经过hook，
+/// 碰巧成功时由这里记录。
+#[cold]
+#[inline(never)]
+unsafe fn retry_new(
+    size: usize,
+    align: usize,
+    family: Family,
+    original: impl Fn() -> *mut c_void,
+) -> *mut c_void {
+    loop {
+        let Some(handler) = ORIGINAL_GET_NEW_HANDLER.get()() else {
+            if !enter_critical_section() {
+                return untracked_alloc(original());
+            }
+            let _section = Section;
+            let ptr = original();
+            if !ptr.is_null() {
+                track_alloc(ptr, size, family);
+            }
+            return ptr;
+        };
+        handler();
+        if !enter_critical_section() {
+            return untracked_alloc(original());
+        }
+        let ptr = new_block(size, align, family);
+        if !ptr.is_null() {
+            return ptr;
+        }
+    }
+}
+
+/// nothrow版本operator new的共同实现
+///
+/// 分配失败时在临界区外调用`original`：libstdc++（9及以后）和libc++的nothrow版本
+/// 在`try`块中调用会抛出异常的版本，也就是上面的hook，块由它记录，`new_handler`抛出的异常也由原始函数捕获。
+#[inline]
+unsafe fn operator_new_nothrow(
+    size: usize,
+    align: usize,
+    family: Family,
+    original: impl FnOnce() -> *mut c_void,
+) -> *mut c_void {
+    if !enter_critical_section() {
+        return untracked_alloc(original());
+    }
+    let ptr = new_block(size, align, family);
+    if !ptr.is_null() {
+        return ptr;
+    }
+    original()
+}
+
+/// 拦截operator new(size_t)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn _Znwm(size: size_t) -> *mut c_void {
+    operator_new(size, 0, Family::New, || ORIGINAL_NEW.get()(size))
+}
+
+/// 拦截operator new[](size_t)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn _Znam(size: size_t) -> *mut c_void {
+    operator_new(size, 0, Family::NewArray, || ORIGINAL_NEW_ARRAY.get()(size))
+}
+
+/// 拦截operator new(size_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZnwmRKSt9nothrow_t(size: size_t, tag: *const c_void) -> *mut c_void {
+    operator_new_nothrow(size, 0, Family::New, || {
+        ORIGINAL_NEW_NOTHROW.get()(size, tag)
+    })
+}
+
+/// 拦截operator new[](size_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZnamRKSt9nothrow_t(size: size_t, tag: *const c_void) -> *mut c_void {
+    operator_new_nothrow(size, 0, Family::NewArray, || {
+        ORIGINAL_NEW_ARRAY_NOTHROW.get()(size, tag)
+    })
+}
+
+/// 拦截operator new(size_t, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn _ZnwmSt11align_val_t(size: size_t, align: size_t) -> *mut c_void {
+    operator_new(size, align, Family::New, || {
+        ORIGINAL_NEW_ALIGNED.get()(size, align)
+    })
+}
+
+/// 拦截operator new[](size_t, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn _ZnamSt11align_val_t(size: size_t, align: size_t) -> *mut c_void {
+    operator_new(size, align, Family::NewArray, || {
+        ORIGINAL_NEW_ARRAY_ALIGNED.get()(size, align)
+    })
+}
+
+/// 拦截operator new(size_t, std::align_val_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZnwmSt11align_val_tRKSt9nothrow_t(
+    size: size_t,
+    align: size_t,
+    tag: *const c_void,
+) -> *mut c_void {
+    operator_new_nothrow(size, align, Family::New, || {
+        ORIGINAL_NEW_ALIGNED_NOTHROW.get()(size, align, tag)
+    })
+}
+
+/// 拦截operator new[](size_t, std::align_val_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZnamSt11align_val_tRKSt9nothrow_t(
+    size: size_t,
+    align: size_t,
+    tag: *const c_void,
+) -> *mut c_void {
+    operator_new_nothrow(size, align, Family::NewArray, || {
+        ORIGINAL_NEW_ARRAY_ALIGNED_NOTHROW.get()(size, align, tag)
+    })
+}
+
+/// 拦截operator delete(void*)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPv(ptr: *mut c_void) {
+    deallocate(ptr, Family::New);
+}
+
+/// 拦截operator delete[](void*)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPv(ptr: *mut c_void) {
+    deallocate(ptr, Family::NewArray);
+}
+
+/// 拦截operator delete(void*, size_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvm(ptr: *mut c_void, _size: size_t) {
+    deallocate(ptr, Family::New);
+}
+
+/// 拦截operator delete[](void*, size_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvm(ptr: *mut c_void, _size: size_t) {
+    deallocate(ptr, Family::NewArray);
+}
+
+/// 拦截operator delete(void*, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvSt11align_val_t(ptr: *mut c_void, _align: size_t) {
+    deallocate(ptr, Family::New);
+}
+
+/// 拦截operator delete[](void*, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvSt11align_val_t(ptr: *mut c_void, _align: size_t) {
+    deallocate(ptr, Family::NewArray);
+}
+
+/// 拦截operator delete(void*, size_t, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvmSt11align_val_t(ptr: *mut c_void, _size: size_t, _align: size_t) {
+    deallocate(ptr, Family::New);
+}
+
+/// 拦截operator delete[](void*, size_t, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvmSt11align_val_t(ptr: *mut c_void, _size: size_t, _align: size_t) {
+    deallocate(ptr, Family::NewArray);
+}
+
+/// 拦截operator delete(void*, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvRKSt9nothrow_t(ptr: *mut c_void, _tag: *const c_void) {
+    deallocate(ptr, Family::New);
+}
+
+/// 拦截operator delete[](void*, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvRKSt9nothrow_t(ptr: *mut c_void, _tag: *const c_void) {
+    deallocate(ptr, Family::NewArray);
+}
+
+/// 拦截operator delete(void*, std::align_val_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvSt11align_val_tRKSt9nothrow_t(
+    ptr: *mut c_void,
+    _align: size_t,
+    _tag: *const c_void,
+) {
+    deallocate(ptr, Family::New);
+}
+
+/// 拦截operator delete[](void*, std::align_val_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvSt11align_val_tRKSt9nothrow_t(
+    ptr: *mut c_void,
+    _align: size_t,
+    _tag: *const c_void,
+) {
+    deallocate(ptr, Family::NewArray);
+}
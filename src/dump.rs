@@ -0,0 +1,240 @@
+//! 堆快照导出
+//!
+//! 长期运行的进程没有退出时机，可以通过信号让后台线程把当前的存活分配表
+//! 写到带时间戳的文件中。信号处理函数只向管道写一个字节，不加锁也不分配内存，
+//! 真正的导出工作由后台线程完成。每次导出同时拍摄一次快照，并与上一次快照对比。
+
+use crate::report::{symbolize_stack, write_stack};
+use crate::stack::StackId;
+use crate::symbolize::Symbolizer;
+use crate::{config, enter_critical_section, registry, sampling, snapshot, threads};
+use libc::c_int;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// 通知管道的写端，-1表示尚未安装
+static NOTIFY_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// 通知管道的读端，由后台线程使用
+static READ_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// 导出信号处理函数：只写管道，满足异步信号安全的要求
+extern "C" fn dump_signal_handler(_sig: c_int) {
+    let fd = NOTIFY_FD.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    unsafe {
+        let errno = *libc::__errno_location();
+        // 写端是非阻塞的，管道已满说明已有未处理的请求，丢弃即可
+        libc::write(fd, b"d".as_ptr() as *const libc::c_void, 1);
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// 后台导出线程：等待管道通知并写出快照
+fn dump_worker(read_fd: c_int) {
+    // 本线程的分配属于本库自身，始终不追踪
+    enter_critical_section();
+    let mut buffer = [0u8; 64];
+    loop {
+        let n = unsafe {
+            libc::read(
+                read_fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n <= 0 {
+            break;
+        }
+        let id = snapshot::take();
+        match write_dump(None) {
+            Ok(path) => eprintln!("[memleak] Heap snapshot {id} written to {}", path.display()),
+            Err(err) => eprintln!("[memleak] Failed to write heap snapshot: {err}"),
+        }
+        if id > 1 {
+            match snapshot::write_diff(id - 1, id, None) {
+                Ok(path) => eprintln!("[memleak] Heap diff written to {}", path.display()),
+                Err(err) => eprintln!("[memleak] Failed to write heap diff: {err}"),
+            }
+        }
+    }
+}
+
+/// 安装导出信号处理函数并启动后台线程
+pub(crate) fn install(signal: c_int) {
+    let mut fds = [0 as c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        eprintln!(
+            "[memleak] Failed to create dump pipe: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    }
+    let [read_fd, write_fd] = fds;
+    unsafe {
+        let flags = libc::fcntl(write_fd, libc::F_GETFL);
+        libc::fcntl(write_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+
+    let spawned = std::thread::Builder::new()
+        .name("memleak-dump".into())
+        .spawn(move || dump_worker(read_fd));
+    if let Err(err) = spawned {
+        eprintln!("[memleak] Failed to start dump thread: {err}");
+        return;
+    }
+
+    READ_FD.store(read_fd, Ordering::Relaxed);
+    NOTIFY_FD.store(write_fd, Ordering::Relaxed);
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = dump_signal_handler as *const () as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/// fork后在子进程中调用：后台线程没有被复制过来，关闭继承的管道
+///
+/// 信号处理函数保持安装，在重新`install`之前收到的信号被忽略。
+pub(crate) fn reset_after_fork() {
+    for fd in [&NOTIFY_FD, &READ_FD] {
+        let fd = fd.swap(-1, Ordering::Relaxed);
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// 带时间戳的默认输出文件名`memleak-<pid>-<时间><suffix>.txt`
+pub(crate) fn output_path(suffix: &str) -> PathBuf {
+    let mut now: libc::time_t = 0;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buffer = [0u8; 32];
+    let len = unsafe {
+        libc::time(&mut now);
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            c"%Y%m%d-%H%M%S".as_ptr(),
+            &tm,
+        )
+    };
+    let timestamp = String::from_utf8_lossy(&buffer[..len]);
+    let name = format!("memleak-{}-{timestamp}{suffix}.txt", std::process::id());
+    match config::dump_dir() {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// 同一调用栈上的存活块汇总
+struct Site {
+    stack: StackId,
+    blocks: usize,
+    bytes: usize,
+    /// 最早分配的块的时刻
+    oldest: u64,
+    /// 最近分配的块的时刻
+    newest: u64,
+}
+
+/// 写出快照：先按分配点汇总，再逐个列出存活块的地址、大小、存活时长和分配点编号
+fn write_snapshot(out: &mut dyn Write) -> io::Result<()> {
+    let now = registry::now_ns();
+    let mut live = registry::snapshot();
+    let mut sites: HashMap<StackId, Site> = HashMap::new();
+    for (_, block) in &live {
+        let site = sites.entry(block.stack).or_insert(Site {
+            stack: block.stack,
+            blocks: 0,
+            bytes: 0,
+            oldest: block.time,
+            newest: block.time,
+        });
+        site.blocks += block.estimated_blocks();
+        site.bytes += block.estimated_bytes();
+        site.oldest = site.oldest.min(block.time);
+        site.newest = site.newest.max(block.time);
+    }
+    let mut sites: Vec<Site> = sites.into_values().collect();
+    sites.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(b.blocks.cmp(&a.blocks)));
+    let numbers: HashMap<StackId, usize> = sites
+        .iter()
+        .enumerate()
+        .map(|(index, site)| (site.stack, index + 1))
+        .collect();
+
+    let (blocks, bytes) = sites.iter().fold((0, 0), |(blocks, bytes), site| {
+        (blocks + site.blocks, bytes + site.bytes)
+    });
+    writeln!(
+        out,
+        "[memleak] ==== Heap snapshot (pid {}, epoch {}) ====",
+        std::process::id(),
+        snapshot::current_epoch()
+    )?;
+    writeln!(
+        out,
+        "[memleak] {blocks} blocks ({bytes} bytes) outstanding, {} allocation sites",
+        sites.len()
+    )?;
+    sampling::write_note(out)?;
+
+    let mut symbolizer = Symbolizer::new();
+    for (index, site) in sites.iter().enumerate() {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Site #{}: {} bytes in {} blocks, age {:.3}s..{:.3}s, allocated from:",
+            index + 1,
+            site.bytes,
+            site.blocks,
+            now.saturating_sub(site.newest) as f64 / 1e9,
+            now.saturating_sub(site.oldest) as f64 / 1e9,
+        )?;
+        write_stack(out, &symbolize_stack(&mut symbolizer, site.stack))?;
+    }
+
+    // 同一分配点的块排在一起，先分配的在前
+    live.sort_by_key(|(ptr, block)| (numbers[&block.stack], block.time, *ptr));
+    writeln!(out)?;
+    writeln!(out, "[memleak] ==== Live allocations ({}) ====", live.len())?;
+    writeln!(out, "           address       size       age  site  thread")?;
+    for (ptr, block) in &live {
+        write!(
+            out,
+            "{ptr:#18x} {:>10} {:>8.3}s {:>5}  {}",
+            block.size,
+            now.saturating_sub(block.time) as f64 / 1e9,
+            format!("#{}", numbers[&block.stack]),
+            threads::name(block.thread).to_string_lossy()
+        )?;
+        // 被采样的块代表多个块
+        if block.weight > 1 {
+            write!(out, " (sampled, weight {})", block.weight)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// 把当前存活分配表写到文件，未指定路径时使用带时间戳的默认文件名
+///
+/// 调用者需要处于hook的临界区内，导出过程中的分配不会被追踪。
+pub(crate) fn write_dump(path: Option<&Path>) -> io::Result<PathBuf> {
+    let path = path.map_or_else(|| output_path(""), Path::to_path_buf);
+    let mut out = BufWriter::new(File::create(&path)?);
+    write_snapshot(&mut out)?;
+    Ok(path)
+}
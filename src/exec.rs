@@ -0,0 +1,418 @@
+//! 子进程的LD_PRELOAD处理
+//!
+//! 开启`MEMLEAK_STRIP_PRELOAD`后，库加载时从进程环境的`LD_PRELOAD`中移除本库，
+//! 之后经由`system`、`popen`等libc内部路径启动的子进程都不会再加载本库。
+//! 直接调用exec/posix_spawn系列函数时，如果程序名与`MEMLEAK_PRELOAD_ALLOW`中的
+//! 模式匹配，则把加载时的`LD_PRELOAD`放回传给子进程的环境中，否则同样移除本库。
+//! 这些函数可能在vfork出的子进程中调用，改写环境时只使用栈上的缓冲区和加载时准备好的条目。
+
+use crate::suppress::Template;
+use crate::{config, enter_critical_section, exit_critical_section, Original};
+use libc::{c_char, c_int, pid_t, posix_spawn_file_actions_t, posix_spawnattr_t};
+use std::ffi::{CStr, CString};
+use std::sync::OnceLock;
+
+type ExecveFn = unsafe extern "C" fn(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int;
+type PosixSpawnFn = unsafe extern "C" fn(
+    pid: *mut pid_t,
+    path: *const c_char,
+    file_actions: *const posix_spawn_file_actions_t,
+    attrp: *const posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int;
+
+/// 原始execve函数指针
+static ORIGINAL_EXECVE: Original<ExecveFn> = Original::new(c"execve");
+/// 原始execvpe函数指针
+static ORIGINAL_EXECVPE: Original<ExecveFn> = Original::new(c"execvpe");
+/// 原始posix_spawn函数指针
+static ORIGINAL_POSIX_SPAWN: Original<PosixSpawnFn> = Original::new(c"posix_spawn");
+/// 原始posix_spawnp函数指针
+static ORIGINAL_POSIX_SPAWNP: Original<PosixSpawnFn> = Original::new(c"posix_spawnp");
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+const LD_PRELOAD: &[u8] = b"LD_PRELOAD=";
+
+/// 改写环境时栈上指针数组的容量，变量更多时原样传递环境并输出提示
+const MAX_ENV_VARS: usize = 1024;
+
+/// 在栈上移除本库时`LD_PRELOAD`值的最大长度，更长时使用加载时移除的结果
+const MAX_PRELOAD_LEN: usize = 4096;
+
+/// 加载时预先准备好的环境条目：exec路径可能处在vfork的子进程中，与父进程共用堆，不能分配内存
+struct Preload {
+    /// 加载时完整的`LD_PRELOAD`值
+    value: Vec<u8>,
+    /// 允许列表中的程序使用的`LD_PRELOAD=<加载时的值>`
+    original: CString,
+    /// 移除本库之后的`LD_PRELOAD=<值>`，移除后为空时为None
+    stripped: Option<CString>,
+    /// 加上`^`/`$`锚定并预先拆分好的允许列表模式
+    allowlist: Vec<Template>,
+}
+
+/// 未开启`MEMLEAK_STRIP_PRELOAD`或加载时没有`LD_PRELOAD`时为None
+static PRELOAD: OnceLock<Option<Preload>> = OnceLock::new();
+
+/// 本库的文件名，用于在`LD_PRELOAD`中识别本库
+fn own_file_name() -> &'static [u8] {
+    static NAME: OnceLock<Vec<u8>> = OnceLock::new();
+    NAME.get_or_init(|| unsafe {
+        let mut info: libc::Dl_info = std::mem::zeroed();
+        let addr = own_file_name as *const () as *const libc::c_void;
+        if libc::dladdr(addr, &mut info) == 0 || info.dli_fname.is_null() {
+            return Vec::new();
+        }
+        let path = CStr::from_ptr(info.dli_fname).to_bytes();
+        file_name(path).to_vec()
+    })
+}
+
+/// 路径的最后一段
+fn file_name(path: &[u8]) -> &[u8] {
+    path.rsplit(|&byte| byte == b'/').next().unwrap_or(path)
+}
+
+/// `LD_PRELOAD`列表中的各项，以空格或冒号分隔
+fn preload_entries(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value
+        .split(|&byte| byte == b' ' || byte == b':')
+        .filter(|entry| !entry.is_empty())
+}
+
+/// `LD_PRELOAD`的值中是否包含文件名为`own`的库
+fn contains_self(value: &[u8], own: &[u8]) -> bool {
+    preload_entries(value).any(|entry| file_name(entry) == own)
+}
+
+/// 从`LD_PRELOAD`的值中移除文件名为`own`的库，结果以冒号分隔写入`out`
+///
+/// 返回结果的长度，`out`放不下时返回None。结果不会比原值更长。
+fn strip_self(value: &[u8], own: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    for entry in preload_entries(value).filter(|entry| file_name(entry) != own) {
+        let separator = usize::from(len > 0);
+        let end = len + separator + entry.len();
+        let dest = out.get_mut(len..end)?;
+        if separator == 1 {
+            dest[0] = b':';
+        }
+        dest[separator..].copy_from_slice(entry);
+        len = end;
+    }
+    Some(len)
+}
+
+/// 拼出`LD_PRELOAD=<值>`，值为空时返回None
+fn preload_var(value: &[u8]) -> Option<CString> {
+    (!value.is_empty())
+        .then(|| CString::new([LD_PRELOAD, value].concat()).ok())
+        .flatten()
+}
+
+/// 提前解析exec系列函数的原始实现
+pub(crate) fn resolve_originals() {
+    ORIGINAL_EXECVE.resolve();
+    ORIGINAL_EXECVPE.resolve();
+    ORIGINAL_POSIX_SPAWN.resolve();
+    ORIGINAL_POSIX_SPAWNP.resolve();
+}
+
+/// 开启时从进程环境中移除本库，并准备好exec路径中要用到的条目，在`init`中调用
+pub(crate) fn install() {
+    if !config::strip_preload() {
+        let _ = PRELOAD.set(None);
+        return;
+    }
+    let Some(value) = std::env::var_os("LD_PRELOAD") else {
+        let _ = PRELOAD.set(None);
+        return;
+    };
+    let value = value.into_encoded_bytes();
+    let mut stripped = vec![0; value.len()];
+    let len = strip_self(&value, own_file_name(), &mut stripped).unwrap_or(0);
+    stripped.truncate(len);
+    unsafe {
+        if stripped.is_empty() {
+            libc::unsetenv(c"LD_PRELOAD".as_ptr());
+        } else if let Ok(stripped) = CString::new(stripped.as_slice()) {
+            libc::setenv(c"LD_PRELOAD".as_ptr(), stripped.as_ptr(), 1);
+        }
+    }
+    let Some(original) = preload_var(&value) else {
+        let _ = PRELOAD.set(None);
+        return;
+    };
+    let allowlist = config::preload_allowlist()
+        .iter()
+        .map(|pattern| Template::new(&format!("^{pattern}$")))
+        .collect();
+    let _ = PRELOAD.set(Some(Preload {
+        stripped: preload_var(&stripped),
+        value,
+        original,
+        allowlist,
+    }));
+}
+
+/// 程序名是否在允许保留预加载的列表中
+fn allowed(preload: &Preload, path: &CStr) -> bool {
+    let Ok(name) = std::str::from_utf8(file_name(path.to_bytes())) else {
+        return false;
+    };
+    preload
+        .allowlist
+        .iter()
+        .any(|pattern| pattern.matches(name))
+}
+
+/// 环境中的各个变量
+unsafe fn env_vars<'a>(envp: *const *const c_char) -> impl Iterator<Item = *const c_char> + 'a {
+    (0..)
+        .map(move |index| *envp.add(index))
+        .take_while(|var| !var.is_null())
+}
+
+/// 变量是`LD_PRELOAD`时返回它的值
+unsafe fn preload_value<'a>(var: *const c_char) -> Option<&'a [u8]> {
+    CStr::from_ptr(var).to_bytes().strip_prefix(LD_PRELOAD)
+}
+
+/// 按程序名改写传给子进程的环境，不分配内存
+///
+/// 改写后的指针数组写入`vars`，新的`LD_PRELOAD`条目需要时写入`entry`，
+/// 两者都要存活到调用结束。不需要改写（或栈上的数组放不下）时返回false，原环境原样传给子进程。
+unsafe fn rewrite_env(
+    path: *const c_char,
+    envp: *const *const c_char,
+    vars: &mut [*const c_char; MAX_ENV_VARS],
+    entry: &mut [u8; LD_PRELOAD.len() + MAX_PRELOAD_LEN + 1],
+) -> bool {
+    let Some(Some(preload)) = PRELOAD.get() else {
+        return false;
+    };
+    if envp.is_null() || path.is_null() {
+        return false;
+    }
+    let own = own_file_name();
+    let keep = allowed(preload, CStr::from_ptr(path));
+    let current = env_vars(envp).find_map(|var| preload_value(var));
+    let loads_self = env_vars(envp)
+        .filter_map(|var| preload_value(var))
+        .any(|value| contains_self(value, own));
+    if keep == loads_self {
+        return false;
+    }
+
+    // 新的LD_PRELOAD条目：允许的程序使用加载时的值，否则从现有的值中移除本库
+    let new_entry: Option<*const c_char> = if keep {
+        Some(preload.original.as_ptr())
+    } else {
+        match current {
+            Some(value) if value == preload.value.as_slice() => {
+                preload.stripped.as_ref().map(|stripped| stripped.as_ptr())
+            }
+            Some(value) => {
+                let (prefix, rest) = entry.split_at_mut(LD_PRELOAD.len());
+                prefix.copy_from_slice(LD_PRELOAD);
+                match strip_self(value, own, &mut rest[..MAX_PRELOAD_LEN]) {
+                    Some(0) => None,
+                    Some(len) => {
+                        rest[len] = 0;
+                        Some(entry.as_ptr() as *const c_char)
+                    }
+                    None => preload.stripped.as_ref().map(|stripped| stripped.as_ptr()),
+                }
+            }
+            None => None,
+        }
+    };
+
+    // 去掉原有的LD_PRELOAD，再按需要加入新的条目，最后以空指针结尾
+    let mut count = 0;
+    for var in env_vars(envp)
+        .filter(|&var| preload_value(var).is_none())
+        .chain(new_entry)
+    {
+        if count + 1 >= MAX_ENV_VARS {
+            write_stderr(&[
+                b"[memleak] Too many environment variables, LD_PRELOAD not rewritten for ",
+                CStr::from_ptr(path).to_bytes(),
+                b"\n",
+            ]);
+            return false;
+        }
+        vars[count] = var;
+        count += 1;
+    }
+    vars[count] = std::ptr::null();
+    true
+}
+
+/// 不分配内存地把几段内容写到标准错误
+fn write_stderr(parts: &[&[u8]]) {
+    for part in parts {
+        unsafe {
+            libc::write(
+                libc::STDERR_FILENO,
+                part.as_ptr() as *const libc::c_void,
+                part.len(),
+            );
+        }
+    }
+}
+
+/// 改写环境后调用原始函数
+///
+/// 调用原始函数之前就离开临界区：vfork的子进程与父进程共用线程局部变量，
+/// exec成功后不再返回，留下的递归标志会让父进程之后的分配都不再追踪。
+unsafe fn with_env<R>(
+    path: *const c_char,
+    envp: *const *const c_char,
+    call: impl FnOnce(*const *const c_char) -> R,
+) -> R {
+    if !matches!(PRELOAD.get(), Some(Some(_))) || !enter_critical_section() {
+        return call(envp);
+    }
+    let mut vars = [std::ptr::null(); MAX_ENV_VARS];
+    let mut entry = [0; LD_PRELOAD.len() + MAX_PRELOAD_LEN + 1];
+    let rewritten = rewrite_env(path, envp, &mut vars, &mut entry);
+    exit_critical_section();
+    call(if rewritten { vars.as_ptr() } else { envp })
+}
+
+/// 拦截execve函数
+#[no_mangle]
+pub unsafe extern "C" fn execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    with_env(path, envp, |envp| ORIGINAL_EXECVE.get()(path, argv, envp))
+}
+
+/// 拦截execvpe函数
+#[no_mangle]
+pub unsafe extern "C" fn execvpe(
+    file: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    with_env(file, envp, |envp| ORIGINAL_EXECVPE.get()(file, argv, envp))
+}
+
+/// 拦截execv函数：glibc内部直接调用execve，需要单独拦截
+#[no_mangle]
+pub unsafe extern "C" fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    execve(path, argv, environ)
+}
+
+/// 拦截execvp函数：glibc内部直接调用execvpe，需要单独拦截
+#[no_mangle]
+pub unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    execvpe(file, argv, environ)
+}
+
+/// 拦截posix_spawn函数
+#[no_mangle]
+pub unsafe extern "C" fn posix_spawn(
+    pid: *mut pid_t,
+    path: *const c_char,
+    file_actions: *const posix_spawn_file_actions_t,
+    attrp: *const posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    with_env(path, envp as *const *const c_char, |envp| {
+        ORIGINAL_POSIX_SPAWN.get()(
+            pid,
+            path,
+            file_actions,
+            attrp,
+            argv,
+            envp as *const *mut c_char,
+        )
+    })
+}
+
+/// 拦截posix_spawnp函数
+#[no_mangle]
+pub unsafe extern "C" fn posix_spawnp(
+    pid: *mut pid_t,
+    file: *const c_char,
+    file_actions: *const posix_spawn_file_actions_t,
+    attrp: *const posix_spawnattr_t,
+    argv: *const *mut c_char,
+    envp: *const *mut c_char,
+) -> c_int {
+    with_env(file, envp as *const *const c_char, |envp| {
+        ORIGINAL_POSIX_SPAWNP.get()(
+            pid,
+            file,
+            file_actions,
+            attrp,
+            argv,
+            envp as *const *mut c_char,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{contains_self, preload_entries, strip_self};
+
+    const OWN: &[u8] = b"libmemleak.so";
+
+    fn strip(value: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; value.len()];
+        let len = strip_self(value, OWN, &mut out).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn entries_split_on_spaces_and_colons() {
+        let entries: Vec<&[u8]> = preload_entries(b" a.so:b.so  c.so::").collect();
+        assert_eq!(entries, [&b"a.so"[..], b"b.so", b"c.so"]);
+        assert_eq!(preload_entries(b"").count(), 0);
+        assert_eq!(preload_entries(b": :").count(), 0);
+    }
+
+    #[test]
+    fn strip_removes_self_by_file_name() {
+        assert_eq!(strip(b"/opt/lib/libmemleak.so"), b"");
+        assert_eq!(
+            strip(b"libfoo.so /x/libmemleak.so libbar.so"),
+            b"libfoo.so:libbar.so"
+        );
+        assert_eq!(strip(b"libmemleak.so:libmemleak.so"), b"");
+        assert_eq!(
+            strip(b"/x/libmemleak.so.1:libfoo.so"),
+            b"/x/libmemleak.so.1:libfoo.so"
+        );
+        assert_eq!(strip(b"  a.so  b.so "), b"a.so:b.so");
+    }
+
+    #[test]
+    fn strip_reports_short_buffer() {
+        let mut out = [0; 4];
+        assert_eq!(strip_self(b"a.so:b.so", OWN, &mut out), None);
+        assert_eq!(strip_self(b"a.so:libmemleak.so", OWN, &mut out), Some(4));
+        assert_eq!(&out, b"a.so");
+    }
+
+    #[test]
+    fn contains_self_matches_file_name_only() {
+        assert!(contains_self(b"a.so /usr/lib/libmemleak.so", OWN));
+        assert!(!contains_self(b"a.so libmemleak.so.1", OWN));
+        assert!(!contains_self(b"", OWN));
+    }
+}
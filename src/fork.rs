@@ -0,0 +1,106 @@
+//! fork处理
+//!
+//! fork时父进程的其他线程可能正持有本库的锁，子进程中这些锁永远不会被释放。
+//! 通过`pthread_atfork`在fork前按固定顺序锁住所有内部表，fork后在父子进程中
+//! 分别释放。子进程中Tracy的工作线程已不存在，不再向Tracy上报事件；
+//! 继承来的存活分配表按`MEMLEAK_FOLLOW_FORK`清空后重新追踪，或者关闭所有hook。
+//! fork处理函数中只能调用异步信号安全的函数，子进程中的导出线程和控制文件线程
+//! 推迟到子进程下一次经过分配hook时再启动。
+
+use crate::config::{self, ForkPolicy};
+use crate::{
+    control, dump, enter_critical_section, exit_critical_section, misuse, mmap, pageguard,
+    quarantine, redzone, registry, snapshot, stack, stats, threads, HOOKS_ACTIVE, TRACY_ACTIVE,
+};
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 子进程中是否还需要启动后台线程
+static CHILD_START_PENDING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// fork期间持有的锁，只存在于调用fork的线程中
+    static HELD: RefCell<Option<Vec<Box<dyn Any>>>> = const { RefCell::new(None) };
+}
+
+/// fork前：进入临界区并锁住所有内部表
+///
+/// 加锁顺序与正常路径一致：隔离区、快照列表、存活分配表、调用栈仓库，其余几张表互不嵌套。
+extern "C" fn prepare() {
+    // 期间的分配直接交给原始函数，不会再去获取已被锁住的表
+    if !enter_critical_section() {
+        return;
+    }
+    // 先访问线程局部变量再加锁：首次访问时glibc为它登记析构函数会分配内存，
+    // 持有存活分配表的锁时这次分配无法从释放历史中移除
+    HELD.with(|held| {
+        let locks = vec![
+            quarantine::lock_for_fork(),
+            snapshot::lock_for_fork(),
+            registry::lock_for_fork(),
+            stack::lock_for_fork(),
+            mmap::lock_for_fork(),
+            redzone::lock_for_fork(),
+            pageguard::lock_for_fork(),
+            threads::lock_for_fork(),
+            misuse::lock_for_fork(),
+        ];
+        *held.borrow_mut() = Some(locks);
+    });
+}
+
+/// 释放`prepare`持有的锁，`prepare`没有加锁时返回false
+fn release() -> bool {
+    HELD.with(|held| held.borrow_mut().take()).is_some()
+}
+
+/// fork后的父进程：释放锁即可
+extern "C" fn parent() {
+    if release() {
+        exit_critical_section();
+    }
+}
+
+/// fork后的子进程：释放锁并按策略重置追踪状态
+extern "C" fn child() {
+    if !release() {
+        return;
+    }
+    TRACY_ACTIVE.store(false, Ordering::Relaxed);
+    registry::clear();
+    stats::clear();
+    snapshot::clear();
+    mmap::clear();
+    threads::reset_after_fork();
+    dump::reset_after_fork();
+    misuse::reset_after_fork();
+    match config::fork_policy() {
+        ForkPolicy::Fresh => CHILD_START_PENDING.store(true, Ordering::Relaxed),
+        ForkPolicy::Disable => HOOKS_ACTIVE.store(false, Ordering::SeqCst),
+    }
+    exit_critical_section();
+}
+
+/// 在fork出的子进程中启动后台线程，只有第一次调用生效
+///
+/// 需要在hook的临界区内调用。
+#[inline]
+pub(crate) fn start_child_threads() {
+    if CHILD_START_PENDING.load(Ordering::Relaxed)
+        && CHILD_START_PENDING.swap(false, Ordering::Relaxed)
+    {
+        if let Some(signal) = config::dump_signal() {
+            dump::install(signal);
+        }
+        control::start_watcher();
+    }
+}
+
+/// 注册fork处理函数
+pub(crate) fn install() {
+    let ret = unsafe { libc::pthread_atfork(Some(prepare), Some(parent), Some(child)) };
+    if ret != 0 {
+        eprintln!("[memleak] Failed to register fork handlers: {ret}");
+    }
+}
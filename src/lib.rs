@@ -1,11 +1,50 @@
+// 导出的hook与libc同名函数遵循相同的调用约定，不再逐个编写Safety文档
+#![allow(clippy::missing_safety_doc)]
+// mmap系列hook需要通过return_address判断调用者
+#![feature(core_intrinsics)]
+#![allow(internal_features)]
+
 use lazy_static::lazy_static;
-use libc::c_char;
-use libc::{c_void, dlsym, size_t, RTLD_NEXT};
+use libc::{c_int, c_void, dlsym, size_t, RTLD_NEXT};
 use std::cell::Cell;
-use tracy_client::sys::___tracy_emit_memory_alloc_callstack;
-use tracy_client::sys::___tracy_emit_memory_free_callstack;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use tracy_client::sys::{
+    ___tracy_emit_memory_alloc, ___tracy_emit_memory_alloc_callstack,
+    ___tracy_emit_memory_alloc_callstack_named, ___tracy_emit_memory_alloc_named,
+    ___tracy_emit_memory_free, ___tracy_emit_memory_free_callstack,
+    ___tracy_emit_memory_free_callstack_named, ___tracy_emit_memory_free_named,
+    ___tracy_shutdown_profiler,
+};
 use tracy_client::Client;
 
+mod bootstrap;
+mod capi;
+mod config;
+mod control;
+mod cxx;
+mod dump;
+mod exec;
+mod fork;
+mod misuse;
+mod mmap;
+mod pageguard;
+mod quarantine;
+mod reachability;
+mod redzone;
+mod registry;
+mod report;
+mod sampling;
+mod snapshot;
+mod stack;
+mod stats;
+mod suppress;
+mod symbolize;
+mod threads;
+
+use registry::{Block, Family, Freed};
+
 /// 原始malloc函数指针类型
 type MallocFn = unsafe extern "C" fn(size: size_t) -> *mut c_void;
 /// 原始free函数指针类型
@@ -15,56 +54,137 @@ type ReallocFn = unsafe extern "C" fn(ptr: *mut c_void, size: size_t) -> *mut c_
 /// 原始calloc函数指针类型
 type CallocFn = unsafe extern "C" fn(count: size_t, size: size_t) -> *mut c_void;
 
-lazy_static! {
-    /// 原始malloc函数指针
-    static ref ORIGINAL_MALLOC: MallocFn = unsafe {
-        let ptr = dlsym(RTLD_NEXT, b"malloc\0".as_ptr() as *const c_char);
-        if ptr.is_null() {
-            panic!("Failed to resolve original malloc");
-        }
-        std::mem::transmute(ptr)
-    };
+/// 原始posix_memalign函数指针类型
+type PosixMemalignFn =
+    unsafe extern "C" fn(memptr: *mut *mut c_void, alignment: size_t, size: size_t) -> c_int;
+/// 原始aligned_alloc函数指针类型
+type AlignedAllocFn = unsafe extern "C" fn(alignment: size_t, size: size_t) -> *mut c_void;
+/// 原始memalign函数指针类型
+type MemalignFn = unsafe extern "C" fn(alignment: size_t, size: size_t) -> *mut c_void;
+/// 原始valloc函数指针类型
+type VallocFn = unsafe extern "C" fn(size: size_t) -> *mut c_void;
+/// 原始pvalloc函数指针类型
+type PvallocFn = unsafe extern "C" fn(size: size_t) -> *mut c_void;
+/// 原始malloc_usable_size函数指针类型
+type MallocUsableSizeFn = unsafe extern "C" fn(ptr: *mut c_void) -> size_t;
+
+/// 通过`dlsym(RTLD_NEXT, ...)`解析被拦截函数的原始实现
+///
+/// `dlsym`内部的分配由引导区提供，见`bootstrap`模块。
+unsafe fn resolve_original(name: &CStr) -> *mut c_void {
+    let ptr = bootstrap::resolve(|| dlsym(RTLD_NEXT, name.as_ptr()));
+    if ptr.is_null() {
+        panic!("Failed to resolve original {}", name.to_string_lossy());
+    }
+    ptr
+}
 
-    /// 原始free函数指针
-    static ref ORIGINAL_FREE: FreeFn = unsafe {
-        let ptr = dlsym(RTLD_NEXT, b"free\0".as_ptr() as *const c_char);
-        if ptr.is_null() {
-            panic!("Failed to resolve original free");
-        }
-        std::mem::transmute(ptr)
-    };
+/// 被拦截函数的原始实现，解析结果缓存在原子变量中
+///
+/// `init`中会提前解析全部原始函数；在此之前被调用时（其他库的构造函数中分配内存）
+/// 就地解析。多个线程同时解析得到的是同一个地址，无需额外同步。
+pub(crate) struct Original<F> {
+    name: &'static CStr,
+    ptr: AtomicPtr<c_void>,
+    _fn: PhantomData<F>,
+}
 
-    /// 原始realloc函数指针
-    static ref ORIGINAL_REALLOC: ReallocFn = unsafe {
-        let ptr = dlsym(RTLD_NEXT, b"realloc\0".as_ptr() as *const c_char);
-        if ptr.is_null() {
-            panic!("Failed to resolve original realloc");
+impl<F: Copy> Original<F> {
+    pub(crate) const fn new(name: &'static CStr) -> Self {
+        Original {
+            name,
+            ptr: AtomicPtr::new(std::ptr::null_mut()),
+            _fn: PhantomData,
         }
-        std::mem::transmute(ptr)
-    };
+    }
 
-    /// 原始calloc函数指针
-    static ref ORIGINAL_CALLOC: CallocFn = unsafe {
-        let ptr = dlsym(RTLD_NEXT, b"calloc\0".as_ptr() as *const c_char);
+    /// 取得原始函数指针，尚未解析时先解析
+    #[inline(always)]
+    pub(crate) unsafe fn get(&self) -> F {
+        let mut ptr = self.ptr.load(Ordering::Relaxed);
         if ptr.is_null() {
-            panic!("Failed to resolve original calloc");
+            ptr = self.resolve();
         }
-        std::mem::transmute(ptr)
-    };
+        std::mem::transmute_copy(&ptr)
+    }
+
+    #[cold]
+    #[inline(never)]
+    pub(crate) fn resolve(&self) -> *mut c_void {
+        let ptr = unsafe { resolve_original(self.name) };
+        self.ptr.store(ptr, Ordering::Relaxed);
+        ptr
+    }
+}
+
+/// 原始malloc函数指针
+static ORIGINAL_MALLOC: Original<MallocFn> = Original::new(c"malloc");
+/// 原始free函数指针
+static ORIGINAL_FREE: Original<FreeFn> = Original::new(c"free");
+/// 原始realloc函数指针
+static ORIGINAL_REALLOC: Original<ReallocFn> = Original::new(c"realloc");
+/// 原始calloc函数指针
+static ORIGINAL_CALLOC: Original<CallocFn> = Original::new(c"calloc");
+/// 原始posix_memalign函数指针
+static ORIGINAL_POSIX_MEMALIGN: Original<PosixMemalignFn> = Original::new(c"posix_memalign");
+/// 原始aligned_alloc函数指针
+static ORIGINAL_ALIGNED_ALLOC: Original<AlignedAllocFn> = Original::new(c"aligned_alloc");
+/// 原始memalign函数指针
+static ORIGINAL_MEMALIGN: Original<MemalignFn> = Original::new(c"memalign");
+/// 原始valloc函数指针
+static ORIGINAL_VALLOC: Original<VallocFn> = Original::new(c"valloc");
+/// 原始pvalloc函数指针
+static ORIGINAL_PVALLOC: Original<PvallocFn> = Original::new(c"pvalloc");
+/// 原始malloc_usable_size函数指针
+static ORIGINAL_MALLOC_USABLE_SIZE: Original<MallocUsableSizeFn> =
+    Original::new(c"malloc_usable_size");
+
+/// 提前解析所有分配函数的原始实现
+fn resolve_originals() {
+    ORIGINAL_MALLOC.resolve();
+    ORIGINAL_FREE.resolve();
+    ORIGINAL_REALLOC.resolve();
+    ORIGINAL_CALLOC.resolve();
+    ORIGINAL_POSIX_MEMALIGN.resolve();
+    ORIGINAL_ALIGNED_ALLOC.resolve();
+    ORIGINAL_MEMALIGN.resolve();
+    ORIGINAL_VALLOC.resolve();
+    ORIGINAL_PVALLOC.resolve();
+    ORIGINAL_MALLOC_USABLE_SIZE.resolve();
+    mmap::resolve_originals();
+    exec::resolve_originals();
 }
 
 // ============================================================================
 // 递归防护机制
 // ============================================================================
 
+/// hook总开关，关闭后所有调用直接转发给原始函数
+static HOOKS_ACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// 是否向Tracy上报事件
+///
+/// Tracy在`init`中启动后才开启，退出时关闭Tracy之前清除。之前记录的块和映射没有上报
+/// 分配事件，记录中带有标记，释放时也不上报。
+/// fork出的子进程中Tracy的工作线程已不存在，不再上报，退出时也不关闭Tracy。
+static TRACY_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+fn tracy_active() -> bool {
+    TRACY_ACTIVE.load(Ordering::Relaxed)
+}
+
 thread_local! {
     /// 递归调用防护标志：防止在hook中再次调用malloc导致无限递归
-    static RECURSION_GUARD: Cell<u32> = Cell::new(0);
+    static RECURSION_GUARD: Cell<u32> = const { Cell::new(0) };
 }
 
 /// 进入临界区，增加递归计数
 #[inline]
 fn enter_critical_section() -> bool {
+    if !HOOKS_ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
     RECURSION_GUARD.with(|guard| {
         let current = guard.get();
         if current > 0 {
@@ -85,6 +205,30 @@ fn exit_critical_section() {
     });
 }
 
+/// 当前线程是否处于hook中
+///
+/// hook关闭后`enter_critical_section`同样返回false，借此区分递归调用和hook关闭之后的调用。
+#[inline]
+fn in_critical_section() -> bool {
+    RECURSION_GUARD.with(|guard| guard.get() > 0)
+}
+
+/// 是否记录新的分配，由`memleak_enable`/`memleak_disable`切换
+///
+/// 关闭期间的分配不进入存活分配表，之后的释放也就不会上报给Tracy。
+static TRACKING_ENABLED: AtomicBool = AtomicBool::new(true);
+
+thread_local! {
+    /// `memleak_ignore_begin`的嵌套层数，大于0时当前线程的分配不追踪
+    static IGNORE_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// 当前线程的新分配是否需要追踪
+#[inline]
+fn tracking_allowed() -> bool {
+    TRACKING_ENABLED.load(Ordering::Relaxed) && IGNORE_DEPTH.with(|depth| depth.get() == 0)
+}
+
 /// 全局内存追踪器
 pub struct AllocationTracker {
     client: Client,
@@ -99,6 +243,9 @@ impl AllocationTracker {
     }
 
     pub fn message(&self, msg: &str) {
+        if !tracy_active() {
+            return;
+        }
         self.client.message(msg, 60);
     }
 }
@@ -111,31 +258,332 @@ impl Default for AllocationTracker {
 
 lazy_static! {
     /// 全局内存追踪器实例
-    static ref GLOBAL_TRACKER: AllocationTracker = {
-        let tracker = AllocationTracker::new();
-        tracker
+    static ref GLOBAL_TRACKER: AllocationTracker = AllocationTracker::new();
+}
+
+// ============================================================================
+// Tracy事件上报
+// ============================================================================
+
+/// 上报分配事件，调用栈深度为0时不采集调用栈，返回是否上报
+#[inline]
+unsafe fn emit_alloc(ptr: *const c_void, size: usize) -> bool {
+    if !tracy_active() {
+        return false;
+    }
+    match config::callstack_depth() {
+        0 => ___tracy_emit_memory_alloc(ptr, size, 0),
+        depth => ___tracy_emit_memory_alloc_callstack(ptr, size, depth, 0),
+    }
+    true
+}
+
+/// 上报释放事件，调用栈深度为0时不采集调用栈
+#[inline]
+unsafe fn emit_free(ptr: *const c_void) {
+    if !tracy_active() {
+        return;
+    }
+    match config::callstack_depth() {
+        0 => ___tracy_emit_memory_free(ptr, 0),
+        depth => ___tracy_emit_memory_free_callstack(ptr, depth, 0),
+    }
+}
+
+/// 上报指定内存池的分配事件，返回是否上报
+#[inline]
+unsafe fn emit_alloc_named(ptr: *const c_void, size: usize, name: &'static CStr) -> bool {
+    if !tracy_active() {
+        return false;
+    }
+    match config::callstack_depth() {
+        0 => ___tracy_emit_memory_alloc_named(ptr, size, 0, name.as_ptr()),
+        depth => ___tracy_emit_memory_alloc_callstack_named(ptr, size, depth, 0, name.as_ptr()),
+    }
+    true
+}
+
+/// 上报指定内存池的释放事件
+#[inline]
+unsafe fn emit_free_named(ptr: *const c_void, name: &'static CStr) {
+    if !tracy_active() {
+        return;
+    }
+    match config::callstack_depth() {
+        0 => ___tracy_emit_memory_free_named(ptr, 0, name.as_ptr()),
+        depth => ___tracy_emit_memory_free_callstack_named(ptr, depth, 0, name.as_ptr()),
+    }
+}
+
+/// 堆块所属的Tracy内存池，None表示默认内存池
+///
+/// 不分池时C++分配记到单独的`new`/`new[]`内存池，与C分配区分开。
+#[inline]
+fn block_pool(block: &Block) -> Option<&'static CStr> {
+    match config::pool_mode() {
+        config::PoolMode::Single => match block.family {
+            Family::Malloc => None,
+            Family::New => Some(cxx::NEW_POOL),
+            Family::NewArray => Some(cxx::NEW_ARRAY_POOL),
+        },
+        config::PoolMode::Thread => Some(threads::name(block.thread)),
+        // 块的请求大小记录在存活分配表中，释放时按同样的规则得到分配时的区间
+        config::PoolMode::SizeClass => Some(config::size_classes().pool(block.size)),
+    }
+}
+
+/// 把堆块的分配事件上报到它所属的内存池，返回是否上报
+#[inline]
+unsafe fn emit_block_alloc(ptr: *const c_void, block: &Block) -> bool {
+    match block_pool(block) {
+        None => emit_alloc(ptr, block.size),
+        Some(name) => emit_alloc_named(ptr, block.size, name),
+    }
+}
+
+/// 把堆块的释放事件上报到它分配时所属的内存池，跨线程释放也能匹配
+///
+/// 分配事件没有上报过的块（Tracy启动之前分配的块）不上报释放事件。
+#[inline]
+unsafe fn emit_block_free(ptr: *const c_void, block: &Block) {
+    if !block.emitted {
+        return;
+    }
+    match block_pool(block) {
+        None => emit_free(ptr),
+        Some(name) => emit_free_named(ptr, name),
+    }
+}
+
+// ============================================================================
+// 存活分配追踪
+// ============================================================================
+
+/// 为一次新分配生成记录，不需要追踪时返回None
+#[inline]
+fn new_block(size: usize, family: Family) -> Option<Block> {
+    fork::start_child_threads();
+    // 范围外的块不采集调用栈也不记录，释放时在存活分配表中查不到，不会上报
+    if !config::size_in_range(size) {
+        reachability::note_unrecorded();
+        return None;
+    }
+    threads::register_current();
+    if !tracking_allowed() {
+        reachability::note_unrecorded();
+        return None;
+    }
+    // 未被采样的块同样不记录，释放时不会上报
+    let Some(weight) = sampling::sample(size) else {
+        reachability::note_unrecorded();
+        return None;
     };
+    // 本库自身发起的分配不追踪；不需要调用栈时无法识别，与调用栈深度为0时相同
+    let stack = if config::collect_stacks() {
+        stack::capture()?
+    } else {
+        0
+    };
+    Some(Block {
+        size,
+        stack,
+        time: registry::now_ns(),
+        epoch: snapshot::current_epoch(),
+        thread: threads::current_name(),
+        weight,
+        family,
+        emitted: false,
+    })
+}
+
+/// 记录一次成功的分配：写入存活分配表并上报给Tracy
+#[inline]
+unsafe fn track_alloc(ptr: *mut c_void, size: usize, family: Family) {
+    let Some(block) = new_block(size, family) else {
+        registry::forget_freed(ptr as usize);
+        return;
+    };
+    insert_block(ptr, &block);
+}
+
+/// 是否开启了需要自己布置块的检测模式
+#[inline]
+fn guarded() -> bool {
+    redzone::enabled() || pageguard::enabled()
+}
+
+/// 开启红区或保护页时分配一个新块
+///
+/// 需要追踪的块按选择放到保护页上，否则带红区或照常分配；不追踪的块照常交给原始函数。
+unsafe fn guarded_alloc(size: usize, zeroed: bool, family: Family) -> *mut c_void {
+    let original = || {
+        if zeroed {
+            ORIGINAL_CALLOC.get()(1, size)
+        } else {
+            ORIGINAL_MALLOC.get()(size)
+        }
+    };
+    let Some(block) = new_block(size, family) else {
+        return untracked_alloc(original());
+    };
+    let mut ptr = std::ptr::null_mut();
+    if pageguard::select(size) {
+        // 映射失败时退回普通的分配
+        ptr = pageguard::alloc(size, &block);
+    }
+    if ptr.is_null() {
+        ptr = if redzone::enabled() {
+            redzone::alloc(size, zeroed)
+        } else {
+            original()
+        };
+    }
+    if !ptr.is_null() {
+        insert_block(ptr, &block);
+    }
+    ptr
+}
+
+/// 分配并记录一个新块，开启红区或保护页时由`guarded_alloc`布置
+#[inline]
+unsafe fn alloc_block(size: usize, family: Family) -> *mut c_void {
+    if guarded() {
+        return guarded_alloc(size, false, family);
+    }
+    let ptr = ORIGINAL_MALLOC.get()(size);
+    if !ptr.is_null() {
+        track_alloc(ptr, size, family);
+    }
+    ptr
+}
+
+/// 把块交还给分配器，带红区的块连同红区一起交还，保护页上的块解除映射
+#[inline]
+unsafe fn release_block(ptr: *mut c_void) {
+    if !pageguard::unmap(ptr) && !redzone::release(ptr) {
+        ORIGINAL_FREE.get()(ptr);
+    }
+}
+
+/// 释放一个被追踪的块：保护页上的块保持不可访问，其余放入隔离区或交还给分配器
+#[inline]
+unsafe fn release_tracked(ptr: *mut c_void, freed: Freed) {
+    if !pageguard::release(ptr, freed) {
+        quarantine::release(ptr, freed);
+    }
+}
+
+/// 递归调用中由原始函数完成的分配：不追踪，但地址同样要从释放历史中移除
+#[inline]
+fn untracked_alloc(ptr: *mut c_void) -> *mut c_void {
+    if !ptr.is_null() {
+        registry::forget_freed(ptr as usize);
+    }
+    ptr
+}
+
+/// 记录一次释放，返回被释放块的记录
+///
+/// 只有存活分配表中记录过的块才上报给Tracy，避免Tracy中出现不匹配的释放事件。
+/// `family`是发起释放的函数所属的族，与块的分配函数族不一致时报告，块仍照常释放。
+/// 带红区的块同时检查红区。
+#[inline]
+unsafe fn track_free(ptr: *mut c_void, family: Family, function: &str) -> Option<Freed> {
+    let block = registry::remove(ptr as usize)?;
+    if block.family != family {
+        misuse::report_mismatch(ptr as usize, &block, function);
+    }
+    redzone::verify(ptr, &block);
+    stats::record_free(&block);
+    emit_block_free(ptr, &block);
+    // 释放历史按配置记录释放时的调用栈，隔离区和保护页总是需要
+    let history = config::free_history() > 0 && TRACKING_ENABLED.load(Ordering::Relaxed);
+    let freed = Freed {
+        block,
+        stack: if (history && config::free_stacks())
+            || quarantine::enabled()
+            || pageguard::enabled()
+        {
+            stack::capture().unwrap_or(0)
+        } else {
+            0
+        },
+        thread: threads::current_name(),
+    };
+    if history {
+        registry::record_freed(ptr as usize, freed);
+    }
+    Some(freed)
+}
+
+/// 把块上报给Tracy并写入存活分配表，也用于恢复realloc失败时原块的记录
+#[inline]
+unsafe fn insert_block(ptr: *mut c_void, block: &Block) {
+    let block = Block {
+        emitted: emit_block_alloc(ptr, block),
+        ..*block
+    };
+    registry::insert(ptr as usize, block);
+    stats::record_alloc(&block);
+}
+
+/// 开启隔离区、红区或保护页时的realloc：分配新块并只复制请求大小内的内容，
+/// 旧块按`release_tracked`释放，不交给原始realloc
+unsafe fn moving_realloc(ptr: *mut c_void, size: size_t, freed: Freed) -> *mut c_void {
+    if size == 0 {
+        release_tracked(ptr, freed);
+        return std::ptr::null_mut();
+    }
+    let new_ptr = alloc_block(size, Family::Malloc);
+    if new_ptr.is_null() {
+        insert_block(ptr, &freed.block);
+        return new_ptr;
+    }
+    let len = freed.block.size.min(size);
+    std::ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, len);
+    release_tracked(ptr, freed);
+    new_ptr
+}
+
+/// 重新分配一个不在存活分配表中、带红区或保护页上的块，其他指针返回None
+#[inline]
+unsafe fn guarded_realloc(ptr: *mut c_void, size: size_t) -> Option<*mut c_void> {
+    pageguard::realloc(ptr, size).or_else(|| redzone::realloc(ptr, size))
+}
+
+/// 重新分配引导区中的块，或在符号解析期间重新分配
+///
+/// 引导区的块不能交给原始realloc：解析期间继续从引导区分配，之后改用malloc，
+/// 再把旧内容复制过去。解析期间只会遇到引导区自己分配的块。
+unsafe fn bootstrap_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    let new_ptr = if bootstrap::resolving() {
+        bootstrap::alloc(size, 0)
+    } else {
+        malloc(size)
+    };
+    if !new_ptr.is_null() && bootstrap::contains(ptr) {
+        let len = bootstrap::size_of(ptr).min(size);
+        std::ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, len);
+    }
+    new_ptr
 }
 
 // ============================================================================
 // Hook函数导出 - C兼容符号
 // ============================================================================
 
-const CALLSTACK_DEPTH: i32 = 5;
-
 /// 拦截malloc函数
 #[no_mangle]
-pub extern "C" fn malloc(size: size_t) -> *mut c_void {
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    if bootstrap::resolving() {
+        return bootstrap::alloc(size, 0);
+    }
     if !enter_critical_section() {
         // 递归调用，直接转发给原始malloc
-        return unsafe { (*ORIGINAL_MALLOC)(size) };
+        return untracked_alloc(ORIGINAL_MALLOC.get()(size));
     }
 
-    let ptr = unsafe {
-        let ptr = (*ORIGINAL_MALLOC)(size);
-        ___tracy_emit_memory_alloc_callstack(ptr, size, CALLSTACK_DEPTH, 0);
-        ptr
-    };
+    let ptr = alloc_block(size, Family::Malloc);
 
     exit_critical_section();
     ptr
@@ -143,41 +591,92 @@ pub extern "C" fn malloc(size: size_t) -> *mut c_void {
 
 /// 拦截free函数
 #[no_mangle]
-pub extern "C" fn free(ptr: *mut c_void) {
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    deallocate(ptr, Family::Malloc);
+}
+
+/// free与operator delete的共同实现，`family`是释放函数所属的族
+#[inline]
+unsafe fn deallocate(ptr: *mut c_void, family: Family) {
+    if bootstrap::contains(ptr) {
+        // 引导区的块永不回收
+        return;
+    }
     if !enter_critical_section() {
-        unsafe { (*ORIGINAL_FREE)(ptr) };
+        // hook关闭之后带红区或保护页上的块仍需按布置交还；递归调用中只会遇到本库自己的块
+        if in_critical_section() {
+            ORIGINAL_FREE.get()(ptr);
+        } else {
+            release_block(ptr);
+        }
         return;
     }
 
-    unsafe {
-        ___tracy_emit_memory_free_callstack(ptr, CALLSTACK_DEPTH, 0);
-        (*ORIGINAL_FREE)(ptr)
-    };
+    let function = family.free_name();
+    match track_free(ptr, family, function) {
+        Some(freed) => release_tracked(ptr, freed),
+        // 重复释放或非法释放的指针不交给原始函数
+        None if ptr.is_null() || misuse::check_free(ptr, function) => release_block(ptr),
+        None => {}
+    }
 
     exit_critical_section();
 }
 
 /// 拦截realloc函数
 #[no_mangle]
-pub extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if bootstrap::resolving() || bootstrap::contains(ptr) {
+        return bootstrap_realloc(ptr, size);
+    }
     if !enter_critical_section() {
-        return unsafe { (*ORIGINAL_REALLOC)(ptr, size) };
+        if !in_critical_section() {
+            if let Some(new_ptr) = guarded_realloc(ptr, size) {
+                return untracked_alloc(new_ptr);
+            }
+        }
+        return untracked_alloc(ORIGINAL_REALLOC.get()(ptr, size));
+    }
+    if ptr.is_null() && guarded() {
+        let new_ptr = alloc_block(size, Family::Malloc);
+        exit_critical_section();
+        return new_ptr;
     }
 
-    let new_ptr = unsafe {
-        // 如果旧指针不为空，记录释放事件
-        if !ptr.is_null() {
-            ___tracy_emit_memory_free_callstack(ptr, CALLSTACK_DEPTH, 0);
+    // 如果旧指针不为空，记录释放事件
+    let old_block = if ptr.is_null() {
+        None
+    } else {
+        track_free(ptr, Family::Malloc, "realloc")
+    };
+    if old_block.is_none() && !ptr.is_null() {
+        if !misuse::check_free(ptr, "realloc") {
+            exit_critical_section();
+            return std::ptr::null_mut();
         }
+        // fork前继承来的带红区或保护页上的块不在存活分配表中
+        if let Some(new_ptr) = guarded_realloc(ptr, size) {
+            exit_critical_section();
+            return untracked_alloc(new_ptr);
+        }
+    }
+    if let Some(freed) = old_block.filter(|_| quarantine::enabled() || guarded()) {
+        let new_ptr = moving_realloc(ptr, size, freed);
+        exit_critical_section();
+        return new_ptr;
+    }
 
-        let new_ptr = (*ORIGINAL_REALLOC)(ptr, size);
+    let new_ptr = ORIGINAL_REALLOC.get()(ptr, size);
 
-        // 只在新分配成功时记录分配事件
-        if !new_ptr.is_null() {
-            ___tracy_emit_memory_alloc_callstack(new_ptr, size, CALLSTACK_DEPTH, 0);
+    // 只在新分配成功时记录分配事件
+    if !new_ptr.is_null() {
+        track_alloc(new_ptr, size, Family::Malloc);
+    } else if size != 0 {
+        // realloc失败时原块保持不变，恢复它的记录
+        if let Some(freed) = old_block {
+            insert_block(ptr, &freed.block);
         }
-        new_ptr
-    };
+    }
 
     exit_critical_section();
     new_ptr
@@ -185,33 +684,190 @@ pub extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
 
 /// 拦截calloc函数
 #[no_mangle]
-pub extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+pub unsafe extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+    if bootstrap::resolving() {
+        return bootstrap::alloc(count.saturating_mul(size), 0);
+    }
     if !enter_critical_section() {
-        return unsafe { (*ORIGINAL_CALLOC)(count, size) };
+        return untracked_alloc(ORIGINAL_CALLOC.get()(count, size));
     }
 
-    let ptr = unsafe {
-        let ptr = (*ORIGINAL_CALLOC)(count, size);
-        let total_size = count.saturating_mul(size);
-        if !ptr.is_null() {
-            ___tracy_emit_memory_alloc_callstack(ptr, total_size, CALLSTACK_DEPTH, 0);
+    let ptr = match count.checked_mul(size) {
+        Some(total_size) if guarded() => guarded_alloc(total_size, true, Family::Malloc),
+        _ => {
+            let ptr = ORIGINAL_CALLOC.get()(count, size);
+            if !ptr.is_null() {
+                track_alloc(ptr, count.saturating_mul(size), Family::Malloc);
+            }
+            ptr
         }
-        ptr
     };
+
     exit_critical_section();
     ptr
 }
 
+/// 拦截posix_memalign函数
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: size_t,
+    size: size_t,
+) -> c_int {
+    if bootstrap::resolving() {
+        let ptr = bootstrap::alloc(size, alignment);
+        if ptr.is_null() {
+            return libc::ENOMEM;
+        }
+        *memptr = ptr;
+        return 0;
+    }
+    if !enter_critical_section() {
+        let ret = ORIGINAL_POSIX_MEMALIGN.get()(memptr, alignment, size);
+        if ret == 0 {
+            untracked_alloc(*memptr);
+        }
+        return ret;
+    }
+
+    let ret = ORIGINAL_POSIX_MEMALIGN.get()(memptr, alignment, size);
+    // 失败时*memptr不会被修改，只在返回0时记录
+    if ret == 0 {
+        track_alloc(*memptr, size, Family::Malloc);
+    }
+
+    exit_critical_section();
+    ret
+}
+
+/// 拦截aligned_alloc函数
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(alignment: size_t, size: size_t) -> *mut c_void {
+    if bootstrap::resolving() {
+        return bootstrap::alloc(size, alignment);
+    }
+    if !enter_critical_section() {
+        return untracked_alloc(ORIGINAL_ALIGNED_ALLOC.get()(alignment, size));
+    }
+
+    let ptr = ORIGINAL_ALIGNED_ALLOC.get()(alignment, size);
+    if !ptr.is_null() {
+        track_alloc(ptr, size, Family::Malloc);
+    }
+
+    exit_critical_section();
+    ptr
+}
+
+/// 拦截memalign函数
+#[no_mangle]
+pub unsafe extern "C" fn memalign(alignment: size_t, size: size_t) -> *mut c_void {
+    if bootstrap::resolving() {
+        return bootstrap::alloc(size, alignment);
+    }
+    if !enter_critical_section() {
+        return untracked_alloc(ORIGINAL_MEMALIGN.get()(alignment, size));
+    }
+
+    let ptr = ORIGINAL_MEMALIGN.get()(alignment, size);
+    if !ptr.is_null() {
+        track_alloc(ptr, size, Family::Malloc);
+    }
+
+    exit_critical_section();
+    ptr
+}
+
+/// 拦截valloc函数
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: size_t) -> *mut c_void {
+    if bootstrap::resolving() {
+        return bootstrap::alloc(size, mmap::page_size());
+    }
+    if !enter_critical_section() {
+        return untracked_alloc(ORIGINAL_VALLOC.get()(size));
+    }
+
+    let ptr = ORIGINAL_VALLOC.get()(size);
+    if !ptr.is_null() {
+        track_alloc(ptr, size, Family::Malloc);
+    }
+
+    exit_critical_section();
+    ptr
+}
+
+/// 拦截pvalloc函数
+///
+/// pvalloc会把大小向上取整到页大小，这里仍然记录调用者请求的大小
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+    if bootstrap::resolving() {
+        return bootstrap::alloc(size, mmap::page_size());
+    }
+    if !enter_critical_section() {
+        return untracked_alloc(ORIGINAL_PVALLOC.get()(size));
+    }
+
+    let ptr = ORIGINAL_PVALLOC.get()(size);
+    if !ptr.is_null() {
+        track_alloc(ptr, size, Family::Malloc);
+    }
+
+    exit_critical_section();
+    ptr
+}
+
+/// 拦截malloc_usable_size函数，带红区或保护页上的块返回请求的大小
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t {
+    if bootstrap::contains(ptr) {
+        return bootstrap::size_of(ptr);
+    }
+    if !in_critical_section() {
+        if let Some(size) = pageguard::size_of(ptr).or_else(|| redzone::size_of(ptr)) {
+            return size;
+        }
+    }
+    ORIGINAL_MALLOC_USABLE_SIZE.get()(ptr)
+}
+
 // ============================================================================
 // 库初始化和清理
 // ============================================================================
 
 #[ctor::ctor]
 fn init() {
+    // 初始化过程中的分配属于本库自身，不应出现在泄漏报告中
+    enter_critical_section();
+    resolve_originals();
+    config::load();
+    lazy_static::initialize(&GLOBAL_TRACKER);
+    TRACY_ACTIVE.store(true, Ordering::SeqCst);
+    fork::install();
+    exec::install();
+    stats::install();
+    control::install();
+    pageguard::install();
+    if let Some(signal) = config::dump_signal() {
+        dump::install(signal);
+    }
     eprintln!("[memleak] Library loaded");
+    exit_critical_section();
 }
 
 #[ctor::dtor]
 fn finalize() {
+    // 停用所有hook后再输出报告：符号化会大量分配内存，不能递归进入malloc hook
+    // fork出的子进程按策略关闭了hook时不输出报告
+    if HOOKS_ACTIVE.swap(false, Ordering::SeqCst) {
+        quarantine::verify_all();
+        misuse::flush();
+        report::write();
+    }
+    // 只有启动了Tracy的进程才关闭它：子进程中关闭会等待不存在的工作线程
+    if TRACY_ACTIVE.swap(false, Ordering::SeqCst) {
+        unsafe { ___tracy_shutdown_profiler() };
+    }
     eprintln!("[memleak] Library unloaded");
 }
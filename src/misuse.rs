@@ -0,0 +1,324 @@
+//! 重复释放、非法释放、分配释放函数不匹配、释放后写入与越界写入检测
+//!
+//! 释放的指针不在存活分配表中时，先查释放历史、隔离区和保护页：命中说明块已经被释放过，是重复释放。
+//! 否则在按地址排序的存活分配表中查找包含它的块：找到说明释放的是块内部的指针。
+//! 分配器返回的地址至少按两个指针宽度对齐，不对齐的指针一定不是块的起始地址，即使找不到
+//! 包含它的块也是非法释放。对齐且不在任何存活块内的指针照常交给原始函数，它们可能属于
+//! 不追踪的块（初始化之前、关闭追踪期间、大小范围外或未被采样的分配）。
+//!
+//! 检测到错误时向Tracy发一条带调用栈的红色消息，在标准错误中输出相关的调用栈，
+//! 不把指针交给原始函数，以免破坏分配器的内部状态；开启`MEMLEAK_ABORT_ON_ERROR`时中止进程。
+//! hook中只采集调用栈，符号化和输出由后台线程完成，退出时输出还没处理的报告；
+//! 中止进程前等待后台线程输出这份报告。
+//! 释放后写入由隔离区在淘汰块时发现，越界写入由红区检查在释放时发现，同样经由这里报告。
+//! 保护页上的越界访问和释放后访问由SIGSEGV处理函数自己输出，那里不能分配内存。
+
+use crate::registry::{self, Block, Freed};
+use crate::report::{symbolize_stack, write_stack};
+use crate::stack::{self, StackId};
+use crate::symbolize::Symbolizer;
+use crate::threads::{self, NameId};
+use crate::{config, enter_critical_section, pageguard, quarantine, tracy_active, HOOKS_ACTIVE};
+use libc::c_void;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use tracy_client::sys::___tracy_emit_messageC;
+
+/// 分配器返回的地址的最小对齐
+const MIN_ALIGNMENT: usize = 2 * std::mem::size_of::<usize>();
+
+/// Tracy中错误消息的颜色
+const ERROR_COLOR: u32 = 0xff0000;
+
+/// 检测到的错误
+enum Misuse {
+    /// 释放一个已经释放过的块
+    DoubleFree(Freed),
+    /// 释放一个不是块起始地址的指针，附带包含它的存活块
+    InvalidPointer(Option<(usize, Block)>),
+    /// 块释放之后仍被写入，附带第一个被改写的字节偏移
+    UseAfterFree(Freed, usize),
+    /// 块的红区被改写，附带离块最近的被改写字节相对块起始的偏移，下溢时为负数
+    Overflow(Block, isize),
+    /// 用与分配函数不同族的函数释放块
+    Mismatch(Block),
+}
+
+/// 检查一次在存活分配表中查不到的释放，返回是否应该交给原始函数
+///
+/// `function`是发起释放的函数名，用于错误信息。需要在hook的临界区内调用。
+pub(crate) fn check_free(ptr: *mut c_void, function: &str) -> bool {
+    let addr = ptr as usize;
+    let misuse = if let Some(freed) = registry::find_freed(addr)
+        .or_else(|| quarantine::find(addr))
+        .or_else(|| pageguard::find_freed(addr))
+    {
+        Misuse::DoubleFree(freed)
+    } else if let Some(containing) = registry::find_containing(addr) {
+        Misuse::InvalidPointer(Some(containing))
+    } else if !addr.is_multiple_of(MIN_ALIGNMENT) {
+        Misuse::InvalidPointer(None)
+    } else {
+        return true;
+    };
+    report(&misuse, addr, function);
+    false
+}
+
+/// 报告隔离区中发现的释放后写入
+pub(crate) fn report_use_after_free(addr: usize, freed: &Freed, offset: usize) {
+    report(&Misuse::UseAfterFree(*freed, offset), addr, "free");
+}
+
+/// 报告红区检查发现的越界写入
+pub(crate) fn report_overflow(addr: usize, block: &Block, offset: isize) {
+    report(&Misuse::Overflow(*block, offset), addr, "free");
+}
+
+/// 报告分配函数与释放函数不匹配，`function`是发起释放的函数名
+pub(crate) fn report_mismatch(addr: usize, block: &Block, function: &str) {
+    report(&Misuse::Mismatch(*block), addr, function);
+}
+
+/// 线程名编号对应的可读名字
+fn thread_name(id: NameId) -> String {
+    threads::name(id).to_string_lossy().into_owned()
+}
+
+/// 报告中一段内容的调用栈
+enum Trace {
+    /// 只有标题
+    Omitted,
+    /// 调用栈仓库中的调用栈
+    Stack(StackId),
+    /// 释放历史没有记录释放调用栈
+    NotRecorded,
+}
+
+/// 一份待输出的错误报告：依次输出的标题和调用栈
+type Report = Vec<(String, Trace)>;
+
+/// 等待后台线程输出的报告
+struct Queue {
+    pending: VecDeque<Report>,
+    /// 累计加入的报告数
+    queued: u64,
+    /// 累计输出的报告数
+    written: u64,
+    /// 后台线程是否已启动
+    worker: bool,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    pending: VecDeque::new(),
+    queued: 0,
+    written: 0,
+    worker: false,
+});
+
+/// 队列有变化时通知后台线程和等待输出的线程
+static QUEUE_CHANGED: Condvar = Condvar::new();
+
+/// 开启`MEMLEAK_ABORT_ON_ERROR`时最多等待报告输出多久再中止进程
+const ABORT_WAIT: Duration = Duration::from_secs(5);
+
+/// 符号化并输出一份报告
+///
+/// 先写到缓冲区再一次性输出，避免与其他线程的输出交错。
+fn write_report(symbolizer: &mut Symbolizer, report: &Report) {
+    let mut out = Vec::new();
+    for (title, trace) in report {
+        let _ = writeln!(out, "[memleak] {title}").and_then(|_| match trace {
+            Trace::Omitted => Ok(()),
+            Trace::Stack(stack) => write_stack(&mut out, &symbolize_stack(symbolizer, *stack)),
+            Trace::NotRecorded => writeln!(out, "    <not recorded, set MEMLEAK_FREE_STACKS=1>"),
+        });
+    }
+    let _ = std::io::stderr().write_all(&out);
+}
+
+/// 后台输出线程：符号化会大量分配内存、读取文件，不能在释放hook中进行
+fn worker() {
+    // 本线程的分配属于本库自身，始终不追踪
+    enter_critical_section();
+    let mut symbolizer = Symbolizer::new();
+    let mut queue = QUEUE.lock().unwrap();
+    loop {
+        match queue.pending.pop_front() {
+            Some(report) => {
+                drop(queue);
+                write_report(&mut symbolizer, &report);
+                queue = QUEUE.lock().unwrap();
+                queue.written += 1;
+                QUEUE_CHANGED.notify_all();
+            }
+            None => queue = QUEUE_CHANGED.wait(queue).unwrap(),
+        }
+    }
+}
+
+/// 把报告交给后台线程，返回它的序号；后台线程在第一次报告时启动
+fn enqueue(report: Report) -> u64 {
+    let mut queue = QUEUE.lock().unwrap();
+    queue.pending.push_back(report);
+    queue.queued += 1;
+    // 退出时hook已经关闭，剩下的报告由`flush`输出
+    if !queue.worker && HOOKS_ACTIVE.load(Ordering::Relaxed) {
+        let spawned = std::thread::Builder::new()
+            .name("memleak-misuse".into())
+            .spawn(worker);
+        match spawned {
+            Ok(_) => queue.worker = true,
+            Err(err) => eprintln!("[memleak] Failed to start error report thread: {err}"),
+        }
+    }
+    QUEUE_CHANGED.notify_all();
+    queue.queued
+}
+
+/// 等待序号为`ticket`的报告输出，最多等待`ABORT_WAIT`，返回是否已经输出
+fn wait_written(ticket: u64) -> bool {
+    let queue = QUEUE.lock().unwrap();
+    let (queue, _) = QUEUE_CHANGED
+        .wait_timeout_while(queue, ABORT_WAIT, |queue| {
+            queue.worker && queue.written < ticket
+        })
+        .unwrap();
+    queue.written >= ticket
+}
+
+/// 输出还没有被后台线程处理的报告，并等待后台线程写完手头的一份，退出时在泄漏报告之前调用
+pub(crate) fn flush() {
+    let (pending, ticket) = {
+        let mut queue = QUEUE.lock().unwrap();
+        let pending = std::mem::take(&mut queue.pending);
+        queue.written += pending.len() as u64;
+        (pending, queue.queued)
+    };
+    let mut symbolizer = Symbolizer::new();
+    for report in &pending {
+        write_report(&mut symbolizer, report);
+    }
+    wait_written(ticket);
+}
+
+/// 锁住报告队列，直到返回值被丢弃，供fork期间保持队列的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(QUEUE.lock().unwrap())
+}
+
+/// fork后在子进程中调用：后台线程没有被复制过来，丢弃父进程的报告，下次报告时重新启动
+pub(crate) fn reset_after_fork() {
+    let mut queue = QUEUE.lock().unwrap();
+    queue.pending.clear();
+    queue.written = queue.queued;
+    queue.worker = false;
+}
+
+/// 向Tracy和标准错误报告一次错误，按配置中止进程
+///
+/// 在释放hook中调用，调用者可能持有任意的锁：这里只采集调用栈，
+/// 符号化和输出交给后台线程，与退出时的泄漏报告相同。
+fn report(misuse: &Misuse, addr: usize, function: &str) {
+    let summary = match misuse {
+        Misuse::DoubleFree(freed) if function == "free" => {
+            format!("double free of {addr:#x} ({} bytes)", freed.block.size)
+        }
+        Misuse::DoubleFree(freed) => format!(
+            "{function} of freed block {addr:#x} ({} bytes)",
+            freed.block.size
+        ),
+        Misuse::InvalidPointer(Some((start, block))) => format!(
+            "invalid {function} of {addr:#x}: {} bytes inside a {}-byte block at {start:#x}",
+            addr - start,
+            block.size
+        ),
+        Misuse::InvalidPointer(None) => {
+            format!("invalid {function} of {addr:#x}: not a heap block")
+        }
+        Misuse::UseAfterFree(freed, offset) => format!(
+            "use-after-free write at offset {offset} of a {}-byte block at {addr:#x}",
+            freed.block.size
+        ),
+        Misuse::Overflow(block, offset) => format!(
+            "heap buffer {} at offset {offset} of a {}-byte block at {addr:#x}",
+            if *offset < 0 { "underflow" } else { "overflow" },
+            block.size
+        ),
+        Misuse::Mismatch(block) => format!(
+            "alloc-dealloc mismatch ({} vs {function}) on {addr:#x} ({} bytes)",
+            block.family.alloc_name(),
+            block.size
+        ),
+    };
+    if tracy_active() {
+        let message = format!("memleak: {summary}");
+        unsafe {
+            ___tracy_emit_messageC(
+                message.as_ptr() as *const libc::c_char,
+                message.len(),
+                ERROR_COLOR,
+                config::callstack_depth(),
+            );
+        }
+    }
+
+    let mut report = Report::new();
+    match misuse {
+        // 写入发生在过去，当前调用栈只是淘汰隔离块的位置，没有意义
+        Misuse::UseAfterFree(..) => report.push((format!("ERROR: {summary}"), Trace::Omitted)),
+        _ => report.push((
+            format!(
+                "ERROR: {summary} in thread {}:",
+                thread_name(threads::current_name())
+            ),
+            Trace::Stack(stack::capture().unwrap_or(0)),
+        )),
+    }
+    match misuse {
+        Misuse::DoubleFree(freed) | Misuse::UseAfterFree(freed, _) => {
+            let title = if matches!(misuse, Misuse::DoubleFree(_)) {
+                "First freed"
+            } else {
+                "Freed"
+            };
+            let trace = if freed.stack == 0 && !config::free_stacks() {
+                Trace::NotRecorded
+            } else {
+                Trace::Stack(freed.stack)
+            };
+            report.push((
+                format!("{title} in thread {}:", thread_name(freed.thread)),
+                trace,
+            ));
+            report.push((
+                format!("Allocated in thread {}:", thread_name(freed.block.thread)),
+                Trace::Stack(freed.block.stack),
+            ));
+        }
+        Misuse::Overflow(block, _) | Misuse::Mismatch(block) => report.push((
+            format!("Allocated in thread {}:", thread_name(block.thread)),
+            Trace::Stack(block.stack),
+        )),
+        Misuse::InvalidPointer(Some((_, block))) => report.push((
+            format!("Block allocated in thread {}:", thread_name(block.thread)),
+            Trace::Stack(block.stack),
+        )),
+        Misuse::InvalidPointer(None) => {}
+    }
+    let ticket = enqueue(report);
+    if config::abort_on_error() {
+        // 退出时不在hook中，直接输出；否则等待后台线程，
+        // 它符号化时不需要当前线程持有的锁，等待超时说明它被卡住，直接中止
+        if !HOOKS_ACTIVE.load(Ordering::Relaxed) {
+            flush();
+        } else if !wait_written(ticket) {
+            eprintln!("[memleak] ERROR: {summary} (callstacks not written in time)");
+        }
+        std::process::abort();
+    }
+}
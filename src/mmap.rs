@@ -0,0 +1,280 @@
+//! mmap系列函数拦截
+//!
+//! mmap分配的内存在Tracy中记录到独立的"mmap"内存池，与malloc堆分开显示。
+//! 由于munmap/mremap可以只作用于映射的一部分，这里维护一张区间表，
+//! 部分解除映射时只释放对应的页，剩余部分作为新的块重新记录。
+
+use crate::stack;
+use crate::{
+    emit_alloc_named, emit_free_named, enter_critical_section, exit_critical_section,
+    tracking_allowed, tracy_active, Original,
+};
+use libc::{c_int, c_void, off64_t, off_t, size_t, MAP_FAILED, MAP_FIXED, MREMAP_FIXED};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tracy_client::sys::___tracy_emit_memory_alloc_named;
+
+/// 原始mmap函数指针类型
+type MmapFn = unsafe extern "C" fn(
+    addr: *mut c_void,
+    len: size_t,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off_t,
+) -> *mut c_void;
+/// 原始mmap64函数指针类型
+type Mmap64Fn = unsafe extern "C" fn(
+    addr: *mut c_void,
+    len: size_t,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off64_t,
+) -> *mut c_void;
+/// 原始munmap函数指针类型
+type MunmapFn = unsafe extern "C" fn(addr: *mut c_void, len: size_t) -> c_int;
+/// 原始mremap函数指针类型，与libc的声明相同为可变参数函数
+type MremapFn = unsafe extern "C" fn(
+    old_addr: *mut c_void,
+    old_len: size_t,
+    new_len: size_t,
+    flags: c_int,
+    ...
+) -> *mut c_void;
+
+/// 原始mmap函数指针
+static ORIGINAL_MMAP: Original<MmapFn> = Original::new(c"mmap");
+/// 原始mmap64函数指针
+static ORIGINAL_MMAP64: Original<Mmap64Fn> = Original::new(c"mmap64");
+/// 原始munmap函数指针
+static ORIGINAL_MUNMAP: Original<MunmapFn> = Original::new(c"munmap");
+/// 原始mremap函数指针
+static ORIGINAL_MREMAP: Original<MremapFn> = Original::new(c"mremap");
+
+/// 提前解析mmap系列函数的原始实现
+pub(crate) fn resolve_originals() {
+    ORIGINAL_MMAP.resolve();
+    ORIGINAL_MMAP64.resolve();
+    ORIGINAL_MUNMAP.resolve();
+    ORIGINAL_MREMAP.resolve();
+}
+
+/// Tracy中mmap内存池的名称，Tracy按指针区分内存池，必须使用同一个静态字符串
+const MMAP_POOL: &CStr = c"mmap";
+
+/// 已记录的映射区间：起始地址 -> (按页对齐后的长度, 分配事件是否已上报给Tracy)
+static REGIONS: Mutex<BTreeMap<usize, (usize, bool)>> = Mutex::new(BTreeMap::new());
+
+/// 锁住区间表，直到返回值被丢弃，供fork期间保持表的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(REGIONS.lock().unwrap())
+}
+
+/// 清空区间表
+pub(crate) fn clear() {
+    REGIONS.lock().unwrap().clear();
+}
+
+/// 系统页大小
+pub(crate) fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    let size = PAGE_SIZE.load(Ordering::Relaxed);
+    if size != 0 {
+        return size;
+    }
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    PAGE_SIZE.store(size, Ordering::Relaxed);
+    size
+}
+
+/// 将长度向上取整到页大小
+fn page_align(len: usize) -> usize {
+    let page = page_size();
+    len.saturating_add(page - 1) & !(page - 1)
+}
+
+/// 判断调用者是否位于本库内部
+///
+/// Tracy内置的rpmalloc直接使用mmap申请内存，如果把这些映射也上报给Tracy，
+/// 会在Tracy自身的分配器中重入，因此需要跳过来自本模块的调用。
+#[inline]
+fn is_internal_caller(return_address: *const ()) -> bool {
+    stack::is_own_address(return_address as usize)
+}
+
+/// 记录一个新的映射区间
+unsafe fn track_region(addr: *mut c_void, len: usize) {
+    if !tracking_allowed() {
+        return;
+    }
+    let len = page_align(len);
+    let emitted = emit_alloc_named(addr, len, MMAP_POOL);
+    REGIONS
+        .lock()
+        .unwrap()
+        .insert(addr as usize, (len, emitted));
+}
+
+/// 上报部分解除映射后剩余的区间，返回是否上报
+///
+/// 剩余部分不是新的分配点，不再采集调用栈。
+unsafe fn emit_remainder(start: usize, len: usize) -> bool {
+    if !tracy_active() {
+        return false;
+    }
+    ___tracy_emit_memory_alloc_named(start as *const c_void, len, 0, MMAP_POOL.as_ptr());
+    true
+}
+
+/// 释放[addr, addr + len)范围内已记录的映射
+///
+/// 与该范围重叠的区间整体释放，未被解除映射的首尾部分作为新块重新记录。
+/// 返回该范围内是否存在已记录的映射。
+unsafe fn release_range(addr: *mut c_void, len: usize) -> bool {
+    if len == 0 {
+        return false;
+    }
+    let start = addr as usize;
+    let end = start.saturating_add(page_align(len));
+    let mut regions = REGIONS.lock().unwrap();
+
+    let overlapping: Vec<(usize, usize, bool)> = regions
+        .range(..end)
+        .rev()
+        .take_while(|&(&region_start, &(region_len, _))| region_start + region_len > start)
+        .map(|(&region_start, &(region_len, emitted))| (region_start, region_len, emitted))
+        .collect();
+
+    // 分配事件没有上报过的区间，释放和剩余部分都不上报
+    for &(region_start, region_len, emitted) in &overlapping {
+        let region_end = region_start + region_len;
+        regions.remove(&region_start);
+        if emitted {
+            emit_free_named(region_start as *const c_void, MMAP_POOL);
+        }
+
+        if region_start < start {
+            let len = start - region_start;
+            regions.insert(
+                region_start,
+                (len, emitted && emit_remainder(region_start, len)),
+            );
+        }
+        if region_end > end {
+            let len = region_end - end;
+            regions.insert(end, (len, emitted && emit_remainder(end, len)));
+        }
+    }
+
+    !overlapping.is_empty()
+}
+
+/// 拦截mmap函数
+#[no_mangle]
+pub unsafe extern "C" fn mmap(
+    addr: *mut c_void,
+    len: size_t,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off_t,
+) -> *mut c_void {
+    if is_internal_caller(std::intrinsics::return_address()) || !enter_critical_section() {
+        return ORIGINAL_MMAP.get()(addr, len, prot, flags, fd, offset);
+    }
+
+    let ptr = ORIGINAL_MMAP.get()(addr, len, prot, flags, fd, offset);
+    if ptr != MAP_FAILED {
+        // MAP_FIXED会隐式替换掉目标范围内原有的映射
+        if flags & MAP_FIXED != 0 {
+            release_range(ptr, len);
+        }
+        track_region(ptr, len);
+    }
+
+    exit_critical_section();
+    ptr
+}
+
+/// 拦截mmap64函数
+#[no_mangle]
+pub unsafe extern "C" fn mmap64(
+    addr: *mut c_void,
+    len: size_t,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off64_t,
+) -> *mut c_void {
+    if is_internal_caller(std::intrinsics::return_address()) || !enter_critical_section() {
+        return ORIGINAL_MMAP64.get()(addr, len, prot, flags, fd, offset);
+    }
+
+    let ptr = ORIGINAL_MMAP64.get()(addr, len, prot, flags, fd, offset);
+    if ptr != MAP_FAILED {
+        if flags & MAP_FIXED != 0 {
+            release_range(ptr, len);
+        }
+        track_region(ptr, len);
+    }
+
+    exit_critical_section();
+    ptr
+}
+
+/// 拦截munmap函数
+#[no_mangle]
+pub unsafe extern "C" fn munmap(addr: *mut c_void, len: size_t) -> c_int {
+    if is_internal_caller(std::intrinsics::return_address()) || !enter_critical_section() {
+        return ORIGINAL_MUNMAP.get()(addr, len);
+    }
+
+    let ret = ORIGINAL_MUNMAP.get()(addr, len);
+    if ret == 0 {
+        release_range(addr, len);
+    }
+
+    exit_critical_section();
+    ret
+}
+
+/// 拦截mremap函数
+///
+/// mremap是可变参数函数，第5个参数`new_address`只在指定MREMAP_FIXED时传入，也只在此时读取。
+#[no_mangle]
+pub unsafe extern "C" fn mremap(
+    old_addr: *mut c_void,
+    old_len: size_t,
+    new_len: size_t,
+    flags: c_int,
+    mut args: ...
+) -> *mut c_void {
+    let new_addr: *mut c_void = if flags & MREMAP_FIXED != 0 {
+        args.next_arg()
+    } else {
+        std::ptr::null_mut()
+    };
+
+    if is_internal_caller(std::intrinsics::return_address()) || !enter_critical_section() {
+        return ORIGINAL_MREMAP.get()(old_addr, old_len, new_len, flags, new_addr);
+    }
+
+    let ptr = ORIGINAL_MREMAP.get()(old_addr, old_len, new_len, flags, new_addr);
+    if ptr != MAP_FAILED {
+        // 只有原映射被记录过时才记录新的映射，避免把未追踪的映射凭空加入内存池
+        let tracked = release_range(old_addr, old_len);
+        if flags & MREMAP_FIXED != 0 {
+            release_range(ptr, new_len);
+        }
+        if tracked {
+            track_region(ptr, new_len);
+        }
+    }
+
+    exit_critical_section();
+    ptr
+}
@@ -0,0 +1,449 @@
+//! 保护页越界检测
+//!
+//! 与Electric Fence相同：开启`MEMLEAK_GUARD_SIZE`或`MEMLEAK_GUARD_RATE`后，选中的被追踪块
+//! 单独占用一段`mmap`的页面，块尾紧贴其后一个不可访问的保护页，越界访问在发生的那条指令上
+//! 就触发SIGSEGV。块释放后整段页面改为不可访问并保留一段时间，释放后的访问同样立即触发。
+//! SIGSEGV处理函数识别落在这些页面中的地址，报告出错位置以及分配（和释放）的调用栈，
+//! 之后恢复原来的处理方式，让这次访问照常终止进程。其他地址上的SIGSEGV交给原来的处理函数，
+//! 本处理函数保持安装，不影响自己处理SIGSEGV的运行时（JIT、GC等）。出错的线程可能正持有分配器或本库的锁，
+//! 处理函数只用栈上的缓冲区和`write(2)`输出未符号化的返回地址以及可执行映射，
+//! 表被锁住时放弃报告。
+//!
+//! 为保持分配器的最小对齐，块尾与保护页之间最多留有不足对齐大小的空隙，空隙填满红区字节，
+//! 释放时检查。每个块至少占用两页，只适合挑选一部分分配放到保护页上。
+
+use crate::registry::{AddrMap, Block, Freed};
+use crate::stack::{self, StackId, MAX_FRAMES};
+use crate::{config, misuse, mmap, redzone, sampling, ORIGINAL_MALLOC};
+use libc::{c_int, c_void};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::hash::BuildHasherDefault;
+use std::sync::{Mutex, OnceLock};
+
+/// 分配器返回的地址的最小对齐
+const MIN_ALIGNMENT: usize = 2 * std::mem::size_of::<usize>();
+
+/// 一个块占用的页面
+#[derive(Clone, Copy)]
+struct Region {
+    /// 映射的起始地址
+    base: usize,
+    /// 映射的长度，包括保护页
+    len: usize,
+    /// 块的记录
+    block: Block,
+}
+
+impl Region {
+    #[inline]
+    fn contains(&self, addr: usize) -> bool {
+        self.base <= addr && addr < self.base + self.len
+    }
+}
+
+/// 保护页上的块
+struct Pages {
+    /// 存活的块：块地址 -> 页面
+    live: AddrMap<Region>,
+    /// 已释放、仍保持不可访问的块：块地址 -> 页面和释放记录
+    freed: AddrMap<(Region, Freed)>,
+    /// 已释放块的释放顺序
+    order: VecDeque<usize>,
+}
+
+static PAGES: Mutex<Pages> = Mutex::new(Pages {
+    live: HashMap::with_hasher(BuildHasherDefault::new()),
+    freed: HashMap::with_hasher(BuildHasherDefault::new()),
+    order: VecDeque::new(),
+});
+
+/// 安装处理函数之前的SIGSEGV处理方式
+static PREVIOUS_ACTION: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// 是否开启保护页模式
+#[inline]
+pub(crate) fn enabled() -> bool {
+    config::guard_pages()
+}
+
+/// 决定一次`size`字节的分配是否放到保护页上
+#[inline]
+pub(crate) fn select(size: usize) -> bool {
+    if !enabled() || !config::guard_size_in_range(size) {
+        return false;
+    }
+    let rate = config::guard_rate();
+    rate == 1 || sampling::next_uniform() * (rate as f64) < 1.0
+}
+
+/// 在单独的页面上分配一个块，块尾紧贴保护页；映射失败时返回空指针
+///
+/// 新映射的页面内容为0，可以直接用于calloc。需要在hook的临界区内调用。
+pub(crate) unsafe fn alloc(size: usize, block: &Block) -> *mut c_void {
+    let page = mmap::page_size();
+    let Some(data) = size.checked_next_multiple_of(page) else {
+        return std::ptr::null_mut();
+    };
+    let data = data.max(page);
+    let len = data + page;
+    let base = libc::mmap(
+        std::ptr::null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if base == libc::MAP_FAILED {
+        return std::ptr::null_mut();
+    }
+    let base = base as usize;
+    let guard = base + data;
+    if libc::mprotect(guard as *mut c_void, page, libc::PROT_NONE) != 0 {
+        libc::munmap(base as *mut c_void, len);
+        return std::ptr::null_mut();
+    }
+    let ptr = (guard - size) & !(MIN_ALIGNMENT - 1);
+    std::ptr::write_bytes((ptr + size) as *mut u8, redzone::CANARY, guard - ptr - size);
+    let region = Region {
+        base,
+        len,
+        block: *block,
+    };
+    PAGES.lock().unwrap().live.insert(ptr, region);
+    ptr as *mut c_void
+}
+
+/// 保护页上的块的请求大小，其他指针返回None
+pub(crate) fn size_of(ptr: *const c_void) -> Option<usize> {
+    if !enabled() || ptr.is_null() {
+        return None;
+    }
+    let pages = PAGES.lock().unwrap();
+    pages
+        .live
+        .get(&(ptr as usize))
+        .map(|region| region.block.size)
+}
+
+/// 检查块尾与保护页之间的空隙
+fn verify_slack(ptr: usize, region: &Region) {
+    let start = ptr + region.block.size;
+    let page = mmap::page_size();
+    let guard = region.base + region.len - page;
+    let slack = unsafe { std::slice::from_raw_parts(start as *const u8, guard - start) };
+    if let Some(index) = slack.iter().position(|&byte| byte != redzone::CANARY) {
+        let offset = (region.block.size + index) as isize;
+        misuse::report_overflow(ptr, &region.block, offset);
+    }
+}
+
+/// 释放一个保护页上的被追踪块：页面改为不可访问并保留，返回指针是否在保护页上
+///
+/// 超出保留数量时解除最早释放的块的映射。需要在hook的临界区内调用。
+pub(crate) unsafe fn release(ptr: *mut c_void, freed: Freed) -> bool {
+    if !enabled() {
+        return false;
+    }
+    let addr = ptr as usize;
+    let Some(region) = PAGES.lock().unwrap().live.remove(&addr) else {
+        return false;
+    };
+    verify_slack(addr, &region);
+    libc::mprotect(region.base as *mut c_void, region.len, libc::PROT_NONE);
+
+    let mut evicted = Vec::new();
+    {
+        let mut pages = PAGES.lock().unwrap();
+        pages.freed.insert(addr, (region, freed));
+        pages.order.push_back(addr);
+        while pages.order.len() > config::guard_delay() {
+            let Some(old) = pages.order.pop_front() else {
+                break;
+            };
+            if let Some((old_region, _)) = pages.freed.remove(&old) {
+                evicted.push(old_region);
+            }
+        }
+    }
+    for region in evicted {
+        libc::munmap(region.base as *mut c_void, region.len);
+    }
+    true
+}
+
+/// 直接解除一个不在存活分配表中的保护页块的映射，返回指针是否在保护页上
+///
+/// 用于fork前继承来的块，以及hook关闭之后的调用。
+pub(crate) unsafe fn unmap(ptr: *mut c_void) -> bool {
+    if !enabled() || ptr.is_null() {
+        return false;
+    }
+    let Some(region) = PAGES.lock().unwrap().live.remove(&(ptr as usize)) else {
+        return false;
+    };
+    libc::munmap(region.base as *mut c_void, region.len);
+    true
+}
+
+/// 重新分配一个不在存活分配表中的保护页块，新块不在保护页上；其他指针返回None
+pub(crate) unsafe fn realloc(ptr: *mut c_void, size: usize) -> Option<*mut c_void> {
+    let old_size = size_of(ptr)?;
+    if size == 0 {
+        unmap(ptr);
+        return Some(std::ptr::null_mut());
+    }
+    let new_ptr = ORIGINAL_MALLOC.get()(size);
+    if !new_ptr.is_null() {
+        std::ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, old_size.min(size));
+        unmap(ptr);
+    }
+    Some(new_ptr)
+}
+
+/// 查找仍保持不可访问的已释放块，用于识别重复释放
+pub(crate) fn find_freed(ptr: usize) -> Option<Freed> {
+    if !enabled() {
+        return None;
+    }
+    let pages = PAGES.lock().unwrap();
+    pages.freed.get(&ptr).map(|&(_, freed)| freed)
+}
+
+/// 信号处理函数中的输出缓冲区，写满时输出到标准错误
+///
+/// 处理函数可能运行在很小的备用信号栈上，缓冲区不宜过大。
+struct SignalWriter {
+    buffer: [u8; 512],
+    len: usize,
+}
+
+impl Write for SignalWriter {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for chunk in text.as_bytes().chunks(self.buffer.len()) {
+            if self.len + chunk.len() > self.buffer.len() {
+                self.flush();
+            }
+            self.buffer[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+            self.len += chunk.len();
+        }
+        Ok(())
+    }
+}
+
+impl SignalWriter {
+    /// 把缓冲的内容写到标准错误
+    fn flush(&mut self) {
+        let mut written = 0;
+        while written < self.len {
+            let ret = unsafe {
+                libc::write(
+                    libc::STDERR_FILENO,
+                    self.buffer[written..].as_ptr() as *const c_void,
+                    self.len - written,
+                )
+            };
+            if ret <= 0 {
+                break;
+            }
+            written += ret as usize;
+        }
+        self.len = 0;
+    }
+
+    /// 输出一段带标题的未符号化调用栈
+    fn write_frames(&mut self, title: &str, frames: &[usize]) -> fmt::Result {
+        writeln!(self, "[memleak] {title}:")?;
+        if frames.is_empty() {
+            return writeln!(self, "    <no callstack>");
+        }
+        for (index, addr) in frames.iter().enumerate() {
+            writeln!(self, "    #{index} {addr:#018x}")?;
+        }
+        Ok(())
+    }
+
+    /// 输出可执行的映射，用于把返回地址换算成模块内偏移
+    fn write_mappings(&mut self) -> fmt::Result {
+        let fd = unsafe {
+            libc::open(
+                c"/proc/self/maps".as_ptr(),
+                libc::O_RDONLY | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Ok(());
+        }
+        writeln!(self, "[memleak] Executable mappings:")?;
+        let mut chunk = [0u8; 256];
+        // 过长的行被截断
+        let mut line = [0u8; 256];
+        let mut len = 0;
+        loop {
+            let count = unsafe { libc::read(fd, chunk.as_mut_ptr() as *mut c_void, chunk.len()) };
+            if count <= 0 {
+                break;
+            }
+            for &byte in &chunk[..count as usize] {
+                if byte != b'\n' {
+                    if len < line.len() {
+                        line[len] = byte;
+                        len += 1;
+                    }
+                    continue;
+                }
+                // 第二列是权限，如`r-xp`
+                let executable = line[..len]
+                    .split(|&byte| byte == b' ')
+                    .nth(1)
+                    .is_some_and(|perms| perms.get(2) == Some(&b'x'));
+                if executable {
+                    writeln!(
+                        self,
+                        "    {}",
+                        std::str::from_utf8(&line[..len]).unwrap_or("?")
+                    )?;
+                }
+                len = 0;
+            }
+        }
+        unsafe { libc::close(fd) };
+        Ok(())
+    }
+
+    /// 输出调用栈仓库中的一条调用栈，仓库被锁住时注明
+    fn write_stack(&mut self, title: &str, id: StackId) -> fmt::Result {
+        let mut frames = [0usize; MAX_FRAMES];
+        match stack::try_frames(id, &mut frames) {
+            Some(count) => self.write_frames(title, &frames[..count]),
+            None => writeln!(self, "[memleak] {title}: <callstack unavailable>"),
+        }
+    }
+}
+
+/// 落在保护页块页面中的一次访问
+enum Fault {
+    /// 存活块的越界访问：块地址和记录
+    Live(usize, Block),
+    /// 已释放块的访问：块地址和释放记录
+    Freed(usize, Freed),
+}
+
+/// 查找地址所在的保护页块，不在这些页面中或表被持有时返回None
+///
+/// 在SIGSEGV处理函数中调用，不分配内存：表被持有时放弃查找，以免死锁。
+fn find_fault(addr: usize) -> Option<Fault> {
+    let pages = PAGES.try_lock().ok()?;
+    if let Some((&ptr, region)) = pages.live.iter().find(|(_, region)| region.contains(addr)) {
+        return Some(Fault::Live(ptr, region.block));
+    }
+    pages
+        .freed
+        .iter()
+        .find(|(_, (region, _))| region.contains(addr))
+        .map(|(&ptr, &(_, freed))| Fault::Freed(ptr, freed))
+}
+
+/// 报告一次保护页访问，不分配内存
+fn report_fault(addr: usize, fault: Fault) -> fmt::Result {
+    let mut out = SignalWriter {
+        buffer: [0; 512],
+        len: 0,
+    };
+    let mut current = [0usize; MAX_FRAMES];
+    match fault {
+        Fault::Live(ptr, block) => {
+            let offset = addr as isize - ptr as isize;
+            writeln!(
+                out,
+                "[memleak] ERROR: heap buffer {} at offset {offset} of a {}-byte block at {ptr:#x}",
+                if offset < 0 { "underflow" } else { "overflow" },
+                block.size
+            )?;
+            out.write_frames("Accessed at", stack::backtrace_raw(&mut current))?;
+            out.write_stack("Allocated at", block.stack)?;
+        }
+        Fault::Freed(ptr, freed) => {
+            writeln!(
+                out,
+                "[memleak] ERROR: use-after-free access at offset {} of a {}-byte block at {ptr:#x}",
+                addr as isize - ptr as isize,
+                freed.block.size
+            )?;
+            out.write_frames("Accessed at", stack::backtrace_raw(&mut current))?;
+            out.write_stack("Freed at", freed.stack)?;
+            out.write_stack("Allocated at", freed.block.stack)?;
+        }
+    }
+    out.write_mappings()?;
+    out.flush();
+    Ok(())
+}
+
+/// 把不属于保护页的SIGSEGV交给原来的处理函数，本处理函数保持安装
+///
+/// 原来是默认处理或忽略时恢复它，返回后这次访问再次触发，照常终止进程。
+unsafe fn chain_previous(sig: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let Some(previous) = PREVIOUS_ACTION.get() else {
+        return;
+    };
+    match previous.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            libc::sigaction(libc::SIGSEGV, previous, std::ptr::null_mut());
+        }
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                std::mem::transmute(handler);
+            handler(sig, info, context);
+        }
+        handler => {
+            let handler: extern "C" fn(c_int) = std::mem::transmute(handler);
+            handler(sig);
+        }
+    }
+}
+
+/// SIGSEGV处理函数
+///
+/// 保护页访问报告后恢复原来的处理方式，返回后这次访问再次触发；其他访问交给原来的处理函数。
+extern "C" fn segv_handler(sig: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let errno = unsafe { *libc::__errno_location() };
+    let addr = unsafe { (*info).si_addr() } as usize;
+    match find_fault(addr) {
+        Some(fault) => {
+            let _ = report_fault(addr, fault);
+            if let Some(previous) = PREVIOUS_ACTION.get() {
+                unsafe { libc::sigaction(libc::SIGSEGV, previous, std::ptr::null_mut()) };
+            }
+        }
+        None => unsafe { chain_previous(sig, info, context) },
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// 开启保护页模式时安装SIGSEGV处理函数
+pub(crate) fn install() {
+    if !enabled() {
+        return;
+    }
+    // 提前加载backtrace使用的展开库，处理函数中的调用就不会再加载
+    let mut frames = [0usize; MAX_FRAMES];
+    stack::backtrace_raw(&mut frames);
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = segv_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGSEGV, &action, &mut previous) == 0 {
+            let _ = PREVIOUS_ACTION.set(previous);
+        }
+    }
+}
+
+/// 锁住保护页表，直到返回值被丢弃，供fork期间保持表的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(PAGES.lock().unwrap())
+}
@@ -0,0 +1,121 @@
+//! 释放隔离区
+//!
+//! 开启`MEMLEAK_QUARANTINE`后，被追踪的块释放时不立即交还给分配器，而是填满毒化字节后
+//! 按释放顺序放入隔离区。隔离区的总字节数超出预算时淘汰最早的块，淘汰前检查毒化字节
+//! 是否完好：被改写说明释放之后仍有写入，报告释放后写入的位置。进程退出时检查所有仍在
+//! 隔离区中的块。块在隔离期间不会被重新分配，对它的重复释放同样能被识别。
+
+use crate::registry::{AddrMap, Freed};
+use crate::{config, misuse, release_block};
+use libc::c_void;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasherDefault;
+use std::sync::Mutex;
+
+/// 毒化字节
+const POISON: u8 = 0xfd;
+
+/// 隔离区中的块
+struct Entry {
+    ptr: usize,
+    freed: Freed,
+}
+
+/// 按释放顺序排列的隔离块
+struct Quarantine {
+    queue: VecDeque<Entry>,
+    /// 隔离块地址到释放记录的索引，用于识别重复释放
+    index: AddrMap<Freed>,
+    /// 隔离块的总字节数
+    bytes: usize,
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    queue: VecDeque::new(),
+    index: HashMap::with_hasher(BuildHasherDefault::new()),
+    bytes: 0,
+});
+
+/// 是否开启隔离区
+#[inline]
+pub(crate) fn enabled() -> bool {
+    config::quarantine_budget() > 0
+}
+
+/// 检查块的毒化字节，返回第一个被改写的字节偏移
+fn check_poison(entry: &Entry) -> Option<usize> {
+    let bytes =
+        unsafe { std::slice::from_raw_parts(entry.ptr as *const u8, entry.freed.block.size) };
+    bytes.iter().position(|&byte| byte != POISON)
+}
+
+/// 检查并交还一个被淘汰的块
+unsafe fn evict(entry: Entry) {
+    if let Some(offset) = check_poison(&entry) {
+        misuse::report_use_after_free(entry.ptr, &entry.freed, offset);
+    }
+    release_block(entry.ptr as *mut c_void);
+}
+
+/// 释放一个被追踪的块：开启隔离区时毒化后放入隔离区，否则直接交还给分配器
+///
+/// 需要在hook的临界区内调用。
+pub(crate) unsafe fn release(ptr: *mut c_void, freed: Freed) {
+    let budget = config::quarantine_budget();
+    let size = freed.block.size;
+    if budget == 0 || size > budget {
+        release_block(ptr);
+        return;
+    }
+    std::ptr::write_bytes(ptr as *mut u8, POISON, size);
+
+    let mut evicted = Vec::new();
+    {
+        let mut quarantine = QUARANTINE.lock().unwrap();
+        quarantine.queue.push_back(Entry {
+            ptr: ptr as usize,
+            freed,
+        });
+        quarantine.index.insert(ptr as usize, freed);
+        quarantine.bytes += size;
+        while quarantine.bytes > budget {
+            let Some(entry) = quarantine.queue.pop_front() else {
+                break;
+            };
+            quarantine.index.remove(&entry.ptr);
+            quarantine.bytes -= entry.freed.block.size;
+            evicted.push(entry);
+        }
+    }
+    // 在锁外检查和交还，报告错误时需要符号化
+    for entry in evicted {
+        evict(entry);
+    }
+}
+
+/// 查找隔离区中的块
+pub(crate) fn find(ptr: usize) -> Option<Freed> {
+    if !enabled() {
+        return None;
+    }
+    QUARANTINE.lock().unwrap().index.get(&ptr).copied()
+}
+
+/// 检查所有仍在隔离区中的块，进程退出时调用
+pub(crate) fn verify_all() {
+    if !enabled() {
+        return;
+    }
+    let quarantine = QUARANTINE.lock().unwrap();
+    for entry in &quarantine.queue {
+        if let Some(offset) = check_poison(entry) {
+            misuse::report_use_after_free(entry.ptr, &entry.freed, offset);
+        }
+    }
+}
+
+/// 锁住隔离区，直到返回值被丢弃，供fork期间保持隔离区的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(QUARANTINE.lock().unwrap())
+}
@@ -0,0 +1,527 @@
+//! 可达性扫描
+//!
+//! 与LeakSanitizer相同的标记过程：以已加载模块的可写段、各线程的栈、
+//! 寄存器和TLS为根，保守地把其中每个对齐的字当作指针，找到指向存活堆块
+//! （包括指向块内部）的引用，再沿着这些块的内容传递标记。
+//! 之后扫描每个未被标记的块，把它们引用到的其它块标记为间接泄漏，
+//! 剩下的未标记块即为确定泄漏。
+//!
+//! 没有记录在存活分配表中的块（被大小范围过滤掉、没有被采样、暂停追踪或被忽略期间分配的块）不会被扫描，
+//! 它们引用的块也就无法标记。出现过这样的块时，确定泄漏和间接泄漏都降级为可能泄漏。
+//!
+//! 扫描其它线程的寄存器时，通过信号让它们在处理函数中暂停，
+//! 寄存器内容会随信号上下文保存在各自的栈上。信号由`MEMLEAK_SCAN_SIGNAL`指定，
+//! 未指定时挑选一个没有安装处理函数的实时信号，扫描结束后恢复原来的处理方式。
+//!
+//! `dlopen`加载的模块的TLS块由glibc在线程第一次访问时单独分配，不在静态TLS区域中，
+//! 通过线程描述符中的DTV找到它们（只支持x86_64上的glibc）。
+
+use crate::config;
+use crate::stack;
+use crate::threads;
+use libc::{c_int, c_void, dl_phdr_info, size_t};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// 堆块的可达性分类
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum Reachability {
+    /// 没有任何引用
+    DefinitelyLost,
+    /// 只被其它泄漏的块引用
+    IndirectlyLost,
+    /// 没有被扫描到的引用，但可能被未记录的块引用
+    PossiblyLost,
+    /// 仍能从根集合访问到
+    Reachable,
+}
+
+impl Reachability {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Reachability::DefinitelyLost => "definitely lost",
+            Reachability::IndirectlyLost => "indirectly lost",
+            Reachability::PossiblyLost => "possibly lost",
+            Reachability::Reachable => "reachable",
+        }
+    }
+}
+
+/// 是否有分配没有记录在存活分配表中
+static UNRECORDED: AtomicBool = AtomicBool::new(false);
+
+/// 有分配没有被记录时调用，之后的分类不再给出确定泄漏和间接泄漏
+#[inline]
+pub(crate) fn note_unrecorded() {
+    if !UNRECORDED.load(Ordering::Relaxed) {
+        UNRECORDED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 有分配没有被记录时在报告中注明原因
+pub(crate) fn write_note(out: &mut dyn Write) -> io::Result<()> {
+    if !UNRECORDED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    writeln!(
+        out,
+        "[memleak] Some allocations were not recorded and could not be scanned; unreferenced blocks are reported as possibly lost"
+    )
+}
+
+/// 可以同时暂停的线程数上限
+const MAX_SUSPENDED: usize = 256;
+
+/// 等待其它线程响应暂停信号的超时时间
+const SUSPEND_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// 暂停中线程的线程号，处理函数据此找到自己的槽位
+static SUSPEND_TIDS: [AtomicI32; MAX_SUSPENDED] = [const { AtomicI32::new(0) }; MAX_SUSPENDED];
+/// 线程在处理函数中的栈指针，0表示尚未响应
+static SUSPEND_SPS: [AtomicUsize; MAX_SUSPENDED] = [const { AtomicUsize::new(0) }; MAX_SUSPENDED];
+/// 扫描结束后置位，暂停的线程随之恢复
+static RESUME: AtomicBool = AtomicBool::new(false);
+
+/// 暂停信号处理函数：记录栈指针后等待扫描结束
+///
+/// 只使用原子操作和系统调用，满足异步信号安全的要求。
+extern "C" fn suspend_handler(_sig: c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+    let errno = unsafe { *libc::__errno_location() };
+    let tid = unsafe { libc::gettid() };
+    if let Some(slot) = SUSPEND_TIDS
+        .iter()
+        .position(|slot| slot.load(Ordering::Acquire) == tid)
+    {
+        // 信号上下文保存在当前栈上，从它所在的位置开始扫描即可覆盖全部寄存器
+        let sp = (context as usize).min(&errno as *const c_int as usize);
+        SUSPEND_SPS[slot].store(sp, Ordering::Release);
+        while !RESUME.load(Ordering::Acquire) {
+            unsafe { libc::sched_yield() };
+        }
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// 信号当前是否没有安装处理函数
+fn signal_unused(signal: c_int) -> bool {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    let queried = unsafe { libc::sigaction(signal, std::ptr::null(), &mut action) } == 0;
+    queried && action.sa_sigaction == libc::SIG_DFL
+}
+
+/// 暂停线程使用的信号：配置的信号，或者从`SIGRTMAX-3`往下第一个没有被程序使用的实时信号
+fn suspend_signal() -> Option<c_int> {
+    if let Some(signal) = config::scan_signal() {
+        return Some(signal);
+    }
+    let signal = (libc::SIGRTMIN()..=libc::SIGRTMAX() - 3)
+        .rev()
+        .find(|&signal| signal_unused(signal));
+    if signal.is_none() {
+        eprintln!(
+            "[memleak] No unused real-time signal, scanning thread stacks without suspending them"
+        );
+    }
+    signal
+}
+
+/// 暂停除当前线程以外的所有已登记线程
+struct StopTheWorld {
+    /// 没有可用的信号时为None，此时不暂停线程
+    signal: Option<c_int>,
+    old_action: libc::sigaction,
+    threads: Vec<threads::ThreadInfo>,
+}
+
+impl StopTheWorld {
+    fn new() -> Self {
+        let current = unsafe { libc::gettid() };
+        let threads: Vec<_> = threads::list()
+            .into_iter()
+            .filter(|info| info.tid != current)
+            .take(MAX_SUSPENDED)
+            .collect();
+
+        let mut old_action: libc::sigaction = unsafe { std::mem::zeroed() };
+        for slot in &SUSPEND_SPS[..threads.len()] {
+            slot.store(0, Ordering::Release);
+        }
+        let Some(signal) = suspend_signal() else {
+            return StopTheWorld {
+                signal: None,
+                old_action,
+                threads,
+            };
+        };
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = suspend_handler as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigfillset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, &mut old_action);
+        }
+
+        RESUME.store(false, Ordering::Release);
+        let pid = std::process::id() as c_int;
+        for (slot, info) in threads.iter().enumerate() {
+            SUSPEND_TIDS[slot].store(info.tid, Ordering::Release);
+            unsafe { libc::syscall(libc::SYS_tgkill, pid, info.tid, signal) };
+        }
+
+        // 屏蔽了信号或阻塞在不可中断状态的线程不会响应，超时后整段扫描它们的栈
+        let deadline = Instant::now() + SUSPEND_TIMEOUT;
+        while Instant::now() < deadline
+            && (0..threads.len()).any(|slot| SUSPEND_SPS[slot].load(Ordering::Acquire) == 0)
+        {
+            std::thread::yield_now();
+        }
+
+        StopTheWorld {
+            signal: Some(signal),
+            old_action,
+            threads,
+        }
+    }
+
+    /// 第`slot`个暂停线程需要扫描的栈范围
+    ///
+    /// 响应了信号的线程只扫描栈指针以上的部分，否则整段扫描。
+    fn stack_range(&self, slot: usize) -> Option<(usize, usize)> {
+        let (low, high) = self.threads[slot].stack;
+        if high <= low {
+            return None;
+        }
+        let sp = SUSPEND_SPS[slot].load(Ordering::Acquire);
+        if low <= sp && sp < high {
+            Some((sp, high))
+        } else {
+            Some((low, high))
+        }
+    }
+}
+
+impl Drop for StopTheWorld {
+    fn drop(&mut self) {
+        RESUME.store(true, Ordering::Release);
+        for tid in &SUSPEND_TIDS[..self.threads.len()] {
+            tid.store(0, Ordering::Release);
+        }
+        let Some(signal) = self.signal else {
+            return;
+        };
+        // 屏蔽了信号的线程上还挂着没有递送的信号，恢复成默认处理方式后会终止进程：
+        // 先改为忽略，丢弃这些挂起的信号，再恢复原来的处理方式
+        unsafe {
+            let mut ignore: libc::sigaction = std::mem::zeroed();
+            ignore.sa_sigaction = libc::SIG_IGN;
+            libc::sigaction(signal, &ignore, std::ptr::null_mut());
+            libc::sigaction(signal, &self.old_action, std::ptr::null_mut());
+        }
+    }
+}
+
+/// 进程中可读的内存映射，用于保证扫描不会访问未映射的地址
+fn readable_mappings() -> Vec<(usize, usize)> {
+    let Ok(maps) = std::fs::read_to_string("/proc/self/maps") else {
+        return Vec::new();
+    };
+    let mut mappings: Vec<(usize, usize)> = maps
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let perms = fields.next()?;
+            let name = fields.nth(3).unwrap_or("");
+            if !perms.starts_with('r') || name == "[vvar]" || name == "[vsyscall]" {
+                return None;
+            }
+            let start = usize::from_str_radix(start, 16).ok()?;
+            let end = usize::from_str_radix(end, 16).ok()?;
+            Some((start, end))
+        })
+        .collect();
+    mappings.sort_unstable();
+    mappings
+}
+
+unsafe extern "C" fn collect_writable_segments(
+    info: *mut dl_phdr_info,
+    _size: size_t,
+    data: *mut c_void,
+) -> c_int {
+    let info = &*info;
+    let ranges = &mut *(data as *mut Vec<(usize, usize)>);
+    let phdrs = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    for phdr in phdrs {
+        if phdr.p_type != libc::PT_LOAD || phdr.p_flags & libc::PF_W == 0 {
+            continue;
+        }
+        let start = info.dlpi_addr as usize + phdr.p_vaddr as usize;
+        // 本库自身的全局变量中只有内部数据结构，不作为根
+        if stack::is_own_address(start) {
+            continue;
+        }
+        ranges.push((start, start + phdr.p_memsz as usize));
+    }
+    0
+}
+
+/// 已加载模块的可写段（.data/.bss）
+fn global_ranges() -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(collect_writable_segments),
+            &mut ranges as *mut Vec<(usize, usize)> as *mut c_void,
+        );
+    }
+    ranges
+}
+
+unsafe extern "C" fn collect_tls_modules(
+    info: *mut dl_phdr_info,
+    _size: size_t,
+    data: *mut c_void,
+) -> c_int {
+    let info = &*info;
+    let modules = &mut *(data as *mut Vec<(usize, usize)>);
+    if info.dlpi_tls_modid == 0 {
+        return 0;
+    }
+    let phdrs = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    if let Some(phdr) = phdrs.iter().find(|phdr| phdr.p_type == libc::PT_TLS) {
+        modules.push((info.dlpi_tls_modid, phdr.p_memsz as usize));
+    }
+    0
+}
+
+/// 带TLS的模块：(模块编号, TLS块大小)
+fn tls_modules() -> Vec<(usize, usize)> {
+    let mut modules: Vec<(usize, usize)> = Vec::new();
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(collect_tls_modules),
+            &mut modules as *mut Vec<(usize, usize)> as *mut c_void,
+        );
+    }
+    modules
+}
+
+/// 静态TLS区域的大小，通过glibc的私有接口获取
+fn static_tls_size() -> usize {
+    type GetTlsStaticInfo = unsafe extern "C" fn(size: *mut size_t, align: *mut size_t);
+    let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"_dl_get_tls_static_info".as_ptr()) };
+    if sym.is_null() {
+        return 0;
+    }
+    let get: GetTlsStaticInfo = unsafe { std::mem::transmute(sym) };
+    let (mut size, mut align) = (0, 0);
+    unsafe { get(&mut size, &mut align) };
+    size
+}
+
+/// 标记过程使用的堆块索引
+struct Marker<'a> {
+    /// 按地址排序的堆块：(起始地址, 结束地址)
+    blocks: &'a [(usize, usize)],
+    states: Vec<Option<Reachability>>,
+    mappings: Vec<(usize, usize)>,
+    worklist: Vec<usize>,
+}
+
+impl Marker<'_> {
+    /// 查找包含该地址的堆块
+    fn find(&self, addr: usize) -> Option<usize> {
+        let index = self.blocks.partition_point(|&(start, _)| start <= addr);
+        let index = index.checked_sub(1)?;
+        let (start, end) = self.blocks[index];
+        // 零字节的块只接受指向起始地址的引用
+        (addr < end || addr == start).then_some(index)
+    }
+
+    /// 扫描一段内存，把引用到的未标记块标记为指定状态并加入工作队列
+    ///
+    /// `owner`为被扫描内存所属的块，块内指向自身的引用不计入。
+    fn scan(&mut self, start: usize, end: usize, state: Reachability, owner: Option<usize>) {
+        let align = size_of::<usize>();
+        let mut addr = (start + align - 1) & !(align - 1);
+        while addr + align <= end {
+            let value = unsafe { std::ptr::read_volatile(addr as *const usize) };
+            if let Some(index) = self.find(value).filter(|&index| Some(index) != owner) {
+                if self.states[index].is_none() {
+                    self.states[index] = Some(state);
+                    self.worklist.push(index);
+                }
+            }
+            addr += align;
+        }
+    }
+
+    /// 只扫描范围内可读的部分
+    fn scan_root(&mut self, start: usize, end: usize) {
+        let mut index = self
+            .mappings
+            .partition_point(|&(_, map_end)| map_end <= start);
+        while let Some(&(map_start, map_end)) = self.mappings.get(index) {
+            if map_start >= end {
+                break;
+            }
+            self.scan(
+                map_start.max(start),
+                map_end.min(end),
+                Reachability::Reachable,
+                None,
+            );
+            index += 1;
+        }
+    }
+
+    /// 读取一个字，地址不可读时返回None
+    fn read_word(&self, addr: usize) -> Option<usize> {
+        let end = addr.checked_add(size_of::<usize>())?;
+        let index = self
+            .mappings
+            .partition_point(|&(_, map_end)| map_end <= addr);
+        let &(map_start, map_end) = self.mappings.get(index)?;
+        (map_start <= addr && end <= map_end)
+            .then(|| unsafe { std::ptr::read_volatile(addr as *const usize) })
+    }
+
+    /// 扫描线程的DTV以及其中登记的各模块TLS块
+    ///
+    /// glibc在x86_64上把DTV指针放在线程描述符的第二个字中，它指向的数组每项两个字，
+    /// 第-1项是数组长度，第`模块编号`项的第一个字是该模块TLS块的地址，尚未分配时为-1。
+    #[cfg(target_arch = "x86_64")]
+    fn scan_dynamic_tls(&mut self, tp: usize, modules: &[(usize, usize)]) {
+        /// DTV的长度上限，超出说明读到的不是DTV
+        const MAX_DTV_LEN: usize = 1 << 16;
+        const ENTRY: usize = 2 * size_of::<usize>();
+        let Some(dtv) = self.read_word(tp + size_of::<usize>()) else {
+            return;
+        };
+        let Some(len) = dtv
+            .checked_sub(ENTRY)
+            .and_then(|header| self.read_word(header))
+            .filter(|&len| len <= MAX_DTV_LEN)
+        else {
+            return;
+        };
+        // 被追踪的TLS块经由DTV中的指针标记
+        self.scan_root(dtv - ENTRY, dtv + (len + 1) * ENTRY);
+        for &(modid, size) in modules.iter().filter(|&&(modid, _)| modid <= len) {
+            match self.read_word(dtv + modid * ENTRY) {
+                Some(block) if block != 0 && block != usize::MAX => {
+                    self.scan_root(block, block.saturating_add(size))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn scan_dynamic_tls(&mut self, _tp: usize, _modules: &[(usize, usize)]) {}
+
+    /// 处理工作队列，传递标记
+    fn flood(&mut self, state: Reachability) {
+        while let Some(index) = self.worklist.pop() {
+            let (start, end) = self.blocks[index];
+            self.scan(start, end, state, Some(index));
+        }
+    }
+}
+
+/// 扫描当前线程的寄存器和栈，`stack_end`为登记的栈顶，未登记时为0
+#[inline(never)]
+fn scan_current_thread(marker: &mut Marker, stack_end: usize) {
+    // 把寄存器保存到栈上的上下文中，随栈一起扫描
+    let mut context: libc::ucontext_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::getcontext(&mut context) };
+    let sp = &context as *const libc::ucontext_t as usize;
+
+    let stack_end = if stack_end > sp {
+        stack_end
+    } else {
+        // 主线程在退出阶段可能已经注销，退回到包含当前栈的映射
+        marker
+            .mappings
+            .iter()
+            .find(|&&(start, end)| start <= sp && sp < end)
+            .map_or(sp, |&(_, end)| end)
+    };
+    marker.scan_root(sp, stack_end);
+    std::hint::black_box(&context);
+}
+
+/// 对一组存活堆块做可达性分类
+///
+/// `blocks`为按地址排序的(起始地址, 大小)，返回值与之一一对应。
+/// 调用者需要已经停用所有hook。其它线程暂停期间可能持有malloc的锁，
+/// 因此扫描所需的内存都要在暂停之前分配好。
+pub(crate) fn classify(blocks: &[(usize, usize)]) -> Vec<Reachability> {
+    let ranges: Vec<(usize, usize)> = blocks
+        .iter()
+        .map(|&(start, size)| (start, start + size))
+        .collect();
+    let mut marker = Marker {
+        blocks: &ranges,
+        states: vec![None; ranges.len()],
+        mappings: readable_mappings(),
+        // 每个块在一轮标记中最多入队一次
+        worklist: Vec::with_capacity(ranges.len()),
+    };
+    let globals = global_ranges();
+    let tls_size = static_tls_size();
+    let tls_modules = tls_modules();
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let current_tid = unsafe { libc::gettid() };
+    let current_stack_end = threads::list()
+        .iter()
+        .find(|info| info.tid == current_tid)
+        .map_or(0, |info| info.stack.1);
+
+    let world = StopTheWorld::new();
+    for &(start, end) in &globals {
+        marker.scan_root(start, end);
+    }
+    for slot in 0..world.threads.len() {
+        if let Some((start, end)) = world.stack_range(slot) {
+            marker.scan_root(start, end);
+        }
+    }
+    // 静态TLS位于线程指针之下，线程描述符（含pthread_setspecific数据）位于其上，
+    // 动态加载模块的TLS块经由线程描述符中的DTV找到
+    for tp in world
+        .threads
+        .iter()
+        .map(|info| info.pthread as usize)
+        .chain(std::iter::once(unsafe { libc::pthread_self() } as usize))
+    {
+        marker.scan_root(tp.saturating_sub(tls_size), tp + page);
+        marker.scan_dynamic_tls(tp, &tls_modules);
+    }
+    scan_current_thread(&mut marker, current_stack_end);
+    marker.flood(Reachability::Reachable);
+    drop(world);
+
+    // 每个泄漏的块直接引用到的其它泄漏块都是间接泄漏
+    for index in 0..ranges.len() {
+        if marker.states[index] == Some(Reachability::Reachable) {
+            continue;
+        }
+        let (start, end) = ranges[index];
+        marker.scan(start, end, Reachability::IndirectlyLost, Some(index));
+    }
+
+    let unrecorded = UNRECORDED.load(Ordering::Relaxed);
+    marker
+        .states
+        .into_iter()
+        .map(|state| match state {
+            Some(Reachability::Reachable) => Reachability::Reachable,
+            _ if unrecorded => Reachability::PossiblyLost,
+            state => state.unwrap_or(Reachability::DefinitelyLost),
+        })
+        .collect()
+}
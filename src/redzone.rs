@@ -0,0 +1,114 @@
+//! 红区越界检测
+//!
+//! 开启`MEMLEAK_REDZONE`后，被追踪的`malloc`/`calloc`/`realloc`块向原始分配器多申请
+//! 前后两段红区，红区填满固定字节，hook返回红区之间的内部指针。块被释放或重新分配时
+//! 检查两段红区，被改写说明发生了越界写入，按离块最近的被改写字节报告相对块起始的偏移。
+//!
+//! 带红区的块另外登记在一张表中，不依赖存活分配表：fork后子进程清空了存活分配表、
+//! 或者hook已经关闭时，这些块仍能以正确的地址交还给分配器。
+
+use crate::registry::{AddrMap, Block};
+use crate::{config, misuse, ORIGINAL_CALLOC, ORIGINAL_FREE, ORIGINAL_MALLOC};
+use libc::c_void;
+use std::any::Any;
+use std::collections::HashMap;
+use std::hash::BuildHasherDefault;
+use std::sync::Mutex;
+
+/// 红区填充字节
+pub(crate) const CANARY: u8 = 0xfa;
+
+/// 带红区的块：内部指针 -> 请求的大小
+static GUARDED: Mutex<AddrMap<usize>> = Mutex::new(HashMap::with_hasher(BuildHasherDefault::new()));
+
+/// 是否开启红区
+#[inline]
+pub(crate) fn enabled() -> bool {
+    config::redzone() > 0
+}
+
+/// 分配一个带红区的块，返回内部指针
+///
+/// 需要在hook的临界区内调用。
+pub(crate) unsafe fn alloc(size: usize, zeroed: bool) -> *mut c_void {
+    let redzone = config::redzone();
+    let Some(total) = size.checked_add(2 * redzone) else {
+        *libc::__errno_location() = libc::ENOMEM;
+        return std::ptr::null_mut();
+    };
+    let base = if zeroed {
+        ORIGINAL_CALLOC.get()(1, total)
+    } else {
+        ORIGINAL_MALLOC.get()(total)
+    } as *mut u8;
+    if base.is_null() {
+        return std::ptr::null_mut();
+    }
+    std::ptr::write_bytes(base, CANARY, redzone);
+    std::ptr::write_bytes(base.add(redzone + size), CANARY, redzone);
+    let ptr = base.add(redzone) as *mut c_void;
+    GUARDED.lock().unwrap().insert(ptr as usize, size);
+    ptr
+}
+
+/// 带红区的块的请求大小，其他指针返回None
+pub(crate) fn size_of(ptr: *const c_void) -> Option<usize> {
+    if !enabled() || ptr.is_null() {
+        return None;
+    }
+    GUARDED.lock().unwrap().get(&(ptr as usize)).copied()
+}
+
+/// 交还一个带红区的块，返回指针是否带红区；不带红区的指针不做任何处理
+pub(crate) unsafe fn release(ptr: *mut c_void) -> bool {
+    if !enabled() || ptr.is_null() {
+        return false;
+    }
+    if GUARDED.lock().unwrap().remove(&(ptr as usize)).is_none() {
+        return false;
+    }
+    ORIGINAL_FREE.get()((ptr as *mut u8).sub(config::redzone()) as *mut c_void);
+    true
+}
+
+/// 重新分配一个不在存活分配表中的带红区块，新块不带红区；不带红区的指针返回None
+///
+/// 用于fork前继承来的块，以及hook关闭之后的调用。
+pub(crate) unsafe fn realloc(ptr: *mut c_void, size: usize) -> Option<*mut c_void> {
+    let old_size = size_of(ptr)?;
+    if size == 0 {
+        release(ptr);
+        return Some(std::ptr::null_mut());
+    }
+    let new_ptr = ORIGINAL_MALLOC.get()(size);
+    if !new_ptr.is_null() {
+        std::ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, old_size.min(size));
+        release(ptr);
+    }
+    Some(new_ptr)
+}
+
+/// 检查块的红区，按离块最近的被改写字节报告下溢和上溢
+///
+/// 块不带红区时不做任何检查。需要在hook的临界区内调用。
+pub(crate) unsafe fn verify(ptr: *mut c_void, block: &Block) {
+    if size_of(ptr) != Some(block.size) {
+        return;
+    }
+    let redzone = config::redzone();
+    let ptr = ptr as *const u8;
+    let front = std::slice::from_raw_parts(ptr.sub(redzone), redzone);
+    if let Some(index) = front.iter().rposition(|&byte| byte != CANARY) {
+        let offset = index as isize - redzone as isize;
+        misuse::report_overflow(ptr as usize, block, offset);
+    }
+    let back = std::slice::from_raw_parts(ptr.add(block.size), redzone);
+    if let Some(index) = back.iter().position(|&byte| byte != CANARY) {
+        misuse::report_overflow(ptr as usize, block, (block.size + index) as isize);
+    }
+}
+
+/// 锁住红区表，直到返回值被丢弃，供fork期间保持表的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(GUARDED.lock().unwrap())
+}
@@ -0,0 +1,355 @@
+//! 存活分配表
+//!
+//! 记录所有经过hook上报的、尚未释放的堆块，供退出时的泄漏报告使用。
+//! 表按地址所在的1MiB区域分片，降低多线程同时分配时的锁竞争；分片内按地址排序，
+//! 释放非起始地址的指针时可以找到包含它的块。跨越整个区域的大块另有一张按地址排序的表。
+//!
+//! 每个分片同时保存最近释放的块及其释放调用栈，数量有上限，用于识别重复释放。
+//! 地址被任何一次分配重新使用后即从释放历史中移除，包括不追踪的分配。
+//! 关闭追踪期间不维护释放历史，关闭前的记录随之作废。
+
+use crate::stack::StackId;
+use crate::threads::NameId;
+use crate::{config, TRACKING_ENABLED};
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// 分片数量，必须是2的幂
+const SHARD_COUNT: usize = 64;
+
+/// 分片区域大小的对数：同一区域内的块在同一个分片中
+const REGION_SHIFT: u32 = 20;
+
+/// 不小于区域大小的块是大块，起始地址可能在前面任意远的区域中
+const LARGE_SIZE: usize = 1 << REGION_SHIFT;
+
+/// 创建块的分配函数族，块必须由同一族的释放函数释放
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Family {
+    /// malloc/calloc/realloc等C分配函数
+    Malloc,
+    /// C++ operator new
+    New,
+    /// C++ operator new[]
+    NewArray,
+}
+
+impl Family {
+    /// 这一族的分配函数名
+    pub(crate) fn alloc_name(self) -> &'static str {
+        match self {
+            Family::Malloc => "malloc",
+            Family::New => "operator new",
+            Family::NewArray => "operator new []",
+        }
+    }
+
+    /// 这一族的释放函数名
+    pub(crate) fn free_name(self) -> &'static str {
+        match self {
+            Family::Malloc => "free",
+            Family::New => "operator delete",
+            Family::NewArray => "operator delete []",
+        }
+    }
+}
+
+/// 一个存活堆块的信息
+#[derive(Clone, Copy)]
+pub(crate) struct Block {
+    /// 调用者请求的大小
+    pub size: usize,
+    /// 分配时的调用栈
+    pub stack: StackId,
+    /// 分配时刻，单调时钟纳秒数
+    pub time: u64,
+    /// 分配时的快照纪元
+    pub epoch: u32,
+    /// 分配线程的线程名编号
+    pub thread: NameId,
+    /// 采样权重：该块代表的块数，未开启采样时为1
+    pub weight: u32,
+    /// 创建块的分配函数族
+    pub family: Family,
+    /// 分配事件是否已上报给Tracy，释放时据此决定是否上报
+    pub emitted: bool,
+}
+
+impl Block {
+    /// 按采样权重估计的块数
+    #[inline]
+    pub(crate) fn estimated_blocks(&self) -> usize {
+        self.weight as usize
+    }
+
+    /// 按采样权重估计的字节数
+    #[inline]
+    pub(crate) fn estimated_bytes(&self) -> usize {
+        self.size.saturating_mul(self.weight as usize)
+    }
+}
+
+/// 一个已释放堆块的信息
+#[derive(Clone, Copy)]
+pub(crate) struct Freed {
+    /// 释放前的记录
+    pub block: Block,
+    /// 释放时的调用栈
+    pub stack: StackId,
+    /// 释放线程的线程名编号
+    pub thread: NameId,
+}
+
+/// 当前单调时钟纳秒数，使用粗粒度时钟以降低hook开销
+#[inline]
+pub(crate) fn now_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// 地址哈希：地址本身已经足够分散，只做一次乘法打散低位的对齐零位
+#[derive(Default)]
+pub(crate) struct AddrHasher(u64);
+
+impl Hasher for AddrHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8 | byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.0 = (value as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// 以地址为键的哈希表
+pub(crate) type AddrMap<V> = HashMap<usize, V, BuildHasherDefault<AddrHasher>>;
+
+struct Shard {
+    /// 存活堆块，按地址排序
+    live: BTreeMap<usize, Block>,
+    /// 最近释放的堆块，值中带有入队序号和记录时的代数
+    freed: AddrMap<(Freed, u64, u32)>,
+    /// 释放历史的入队顺序，用于淘汰最早的记录；已被移除的记录序号对不上，淘汰时跳过
+    order: VecDeque<(usize, u64)>,
+    /// 下一个入队序号
+    next: u64,
+}
+
+static SHARDS: [Mutex<Shard>; SHARD_COUNT] = [const {
+    Mutex::new(Shard {
+        live: BTreeMap::new(),
+        freed: HashMap::with_hasher(BuildHasherDefault::new()),
+        order: VecDeque::new(),
+        next: 0,
+    })
+}; SHARD_COUNT];
+
+/// 存活的大块：起始地址到大小，只在持有该块所在分片的锁时修改
+static LARGE: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// 存活的大块数量，没有大块时查找不必加锁
+static LARGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// 当前线程持有的分片锁数量
+    static HOLDING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// 分片锁，持有期间标记当前线程，`forget_freed`据此避免在同一线程中重复加锁
+struct Guard(MutexGuard<'static, Shard>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        HOLDING.with(|holding| holding.set(holding.get() - 1));
+    }
+}
+
+impl std::ops::Deref for Guard {
+    type Target = Shard;
+
+    fn deref(&self) -> &Shard {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Guard {
+    fn deref_mut(&mut self) -> &mut Shard {
+        &mut self.0
+    }
+}
+
+/// 释放历史的代数，每次关闭追踪时加一，旧代数的记录不再有效
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// 作废当前所有释放历史，关闭追踪时调用；只修改原子变量，可以在信号处理函数中调用
+pub(crate) fn invalidate_freed() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 当前是否维护释放历史
+#[inline]
+fn history_active() -> bool {
+    config::free_history() > 0 && TRACKING_ENABLED.load(Ordering::Relaxed)
+}
+
+/// 锁住一个分片
+#[inline]
+fn lock(shard: &'static Mutex<Shard>) -> Guard {
+    let guard = shard.lock().unwrap();
+    HOLDING.with(|holding| holding.set(holding.get() + 1));
+    Guard(guard)
+}
+
+/// 区域所在的分片：相邻区域打散到不同分片
+#[inline]
+fn region_shard(region: usize) -> &'static Mutex<Shard> {
+    let hash = (region as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    &SHARDS[(hash >> (64 - SHARD_COUNT.trailing_zeros())) as usize]
+}
+
+/// 地址所在的分片
+#[inline]
+fn shard(ptr: usize) -> &'static Mutex<Shard> {
+    region_shard(ptr >> REGION_SHIFT)
+}
+
+/// 记录一个新分配的堆块，地址同时从释放历史中移除
+pub(crate) fn insert(ptr: usize, block: Block) {
+    let mut shard = lock(shard(ptr));
+    shard.freed.remove(&ptr);
+    shard.live.insert(ptr, block);
+    if block.size >= LARGE_SIZE {
+        LARGE.lock().unwrap().insert(ptr, block.size);
+        LARGE_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// 移除一个堆块，返回其记录；未记录过的指针返回None
+pub(crate) fn remove(ptr: usize) -> Option<Block> {
+    let mut shard = lock(shard(ptr));
+    let block = shard.live.remove(&ptr)?;
+    if block.size >= LARGE_SIZE {
+        LARGE.lock().unwrap().remove(&ptr);
+        LARGE_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+    Some(block)
+}
+
+/// 把一个刚被释放的块加入释放历史，超出上限时淘汰最早的记录
+pub(crate) fn record_freed(ptr: usize, freed: Freed) {
+    if !history_active() {
+        return;
+    }
+    let capacity = config::free_history().div_ceil(SHARD_COUNT);
+    let generation = GENERATION.load(Ordering::Relaxed);
+    let mut shard = lock(shard(ptr));
+    let seq = shard.next;
+    shard.next += 1;
+    shard.freed.insert(ptr, (freed, seq, generation));
+    shard.order.push_back((ptr, seq));
+    while shard.order.len() > capacity {
+        let Some((old, old_seq)) = shard.order.pop_front() else {
+            break;
+        };
+        if shard
+            .freed
+            .get(&old)
+            .is_some_and(|&(_, seq, _)| seq == old_seq)
+        {
+            shard.freed.remove(&old);
+        }
+    }
+}
+
+/// 地址被一次不进入存活分配表的分配重新使用，从释放历史中移除
+///
+/// 本库在持有分片锁时发起的分配也会经过这里，此时跳过：这些内存只在本库内部使用和释放。
+#[inline]
+pub(crate) fn forget_freed(ptr: usize) {
+    if !history_active() || HOLDING.with(|holding| holding.get()) > 0 {
+        return;
+    }
+    lock(shard(ptr)).freed.remove(&ptr);
+}
+
+/// 查找地址在释放历史中的有效记录
+pub(crate) fn find_freed(ptr: usize) -> Option<Freed> {
+    if !history_active() {
+        return None;
+    }
+    let generation = GENERATION.load(Ordering::Relaxed);
+    lock(shard(ptr))
+        .freed
+        .get(&ptr)
+        .filter(|&&(_, _, recorded)| recorded == generation)
+        .map(|&(freed, _, _)| freed)
+}
+
+/// 查找包含该地址的存活堆块，返回块的起始地址和记录
+///
+/// 小块只可能从地址所在的区域或前一个区域开始，大块另外查表。
+pub(crate) fn find_containing(addr: usize) -> Option<(usize, Block)> {
+    let contains = |ptr: usize, size: usize| ptr <= addr && addr < ptr + size.max(1);
+    let region = addr >> REGION_SHIFT;
+    for region in [Some(region), region.checked_sub(1)].into_iter().flatten() {
+        let shard = lock(region_shard(region));
+        // 分片中起始地址不超过addr的最后一个块；块互不重叠，只有它可能包含addr
+        if let Some((&ptr, &block)) = shard.live.range(..=addr).next_back() {
+            if contains(ptr, block.size) {
+                return Some((ptr, block));
+            }
+        }
+    }
+    if LARGE_COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let (ptr, size) = LARGE
+        .lock()
+        .unwrap()
+        .range(..=addr)
+        .next_back()
+        .map(|(&ptr, &size)| (ptr, size))?;
+    if !contains(ptr, size) {
+        return None;
+    }
+    lock(shard(ptr)).live.get(&ptr).map(|&block| (ptr, block))
+}
+
+/// 复制当前所有存活堆块
+pub(crate) fn snapshot() -> Vec<(usize, Block)> {
+    let mut blocks = Vec::new();
+    for shard in &SHARDS {
+        let shard = lock(shard);
+        blocks.extend(shard.live.iter().map(|(&ptr, &block)| (ptr, block)));
+    }
+    blocks
+}
+
+/// 锁住所有分片和大块表，直到返回值被丢弃，供fork期间保持表的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    let shards: Vec<Guard> = SHARDS.iter().map(lock).collect();
+    Box::new((shards, LARGE.lock().unwrap()))
+}
+
+/// 清空存活分配表和释放历史
+pub(crate) fn clear() {
+    for shard in &SHARDS {
+        let mut shard = lock(shard);
+        shard.live.clear();
+        shard.freed.clear();
+        shard.order.clear();
+    }
+    LARGE.lock().unwrap().clear();
+    LARGE_COUNT.store(0, Ordering::Relaxed);
+}
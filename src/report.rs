@@ -0,0 +1,252 @@
+//! 退出时的泄漏报告
+//!
+//! 不连接Tracy也能使用：进程退出时把存活分配表按调用栈聚合，
+//! 输出未释放的块数、总字节数以及占用最多的分配点。
+//! 每个块会先经过可达性扫描，分为确定泄漏、间接泄漏和仍可达三类；
+//! 有分配没有被记录时，没有被引用的块只能算作可能泄漏。
+
+use crate::config::{self, ReportTarget};
+use crate::reachability::{self, Reachability};
+use crate::registry;
+use crate::sampling;
+use crate::stack::{self, StackId};
+use crate::suppress::Suppressions;
+use crate::symbolize::{Symbolized, Symbolizer};
+use crate::threads::{self, NameId};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// 报告中列出的分配点数量
+const TOP_SITES: usize = 20;
+
+/// 同一调用栈、同一可达性分类的泄漏汇总
+struct Site {
+    /// 未做可达性扫描时为None
+    reachability: Option<Reachability>,
+    stack: StackId,
+    blocks: usize,
+    bytes: usize,
+    /// 按分配线程拆分的块数和字节数
+    threads: HashMap<NameId, (usize, usize)>,
+}
+
+/// 打开报告输出
+fn open_output(target: &ReportTarget) -> io::Result<Box<dyn Write>> {
+    match target {
+        ReportTarget::Disabled => unreachable!(),
+        ReportTarget::Stderr => Ok(Box::new(io::stderr())),
+        ReportTarget::File(path) => {
+            let path = path
+                .to_string_lossy()
+                .replace("%p", &std::process::id().to_string());
+            Ok(Box::new(BufWriter::new(File::create(path)?)))
+        }
+    }
+}
+
+/// 按可达性分类和调用栈聚合存活堆块，泄漏的块排在前面，同类按字节数降序排列
+fn collect_sites() -> Vec<Site> {
+    let mut blocks = registry::snapshot();
+    blocks.sort_unstable_by_key(|&(ptr, _)| ptr);
+    let classes: Vec<Option<Reachability>> = if config::reachability_scan() {
+        let ranges: Vec<(usize, usize)> = blocks
+            .iter()
+            .map(|&(ptr, block)| (ptr, block.size))
+            .collect();
+        reachability::classify(&ranges)
+            .into_iter()
+            .map(Some)
+            .collect()
+    } else {
+        vec![None; blocks.len()]
+    };
+
+    let mut sites: HashMap<(Option<Reachability>, StackId), Site> = HashMap::new();
+    for ((_, block), reachability) in blocks.into_iter().zip(classes) {
+        let site = sites.entry((reachability, block.stack)).or_insert(Site {
+            reachability,
+            stack: block.stack,
+            blocks: 0,
+            bytes: 0,
+            threads: HashMap::new(),
+        });
+        site.blocks += block.estimated_blocks();
+        site.bytes += block.estimated_bytes();
+        let thread = site.threads.entry(block.thread).or_default();
+        thread.0 += block.estimated_blocks();
+        thread.1 += block.estimated_bytes();
+    }
+
+    let mut sites: Vec<Site> = sites.into_values().collect();
+    sites.sort_by(|a, b| {
+        a.reachability
+            .cmp(&b.reachability)
+            .then(b.bytes.cmp(&a.bytes))
+            .then(b.blocks.cmp(&a.blocks))
+    });
+    sites
+}
+
+/// 符号化一条调用栈
+pub(crate) fn symbolize_stack(
+    symbolizer: &mut Symbolizer,
+    stack: StackId,
+) -> Vec<(usize, Symbolized)> {
+    stack::frames(stack)
+        .into_iter()
+        .map(|addr| (addr, symbolizer.symbolize(addr)))
+        .collect()
+}
+
+/// 输出一条符号化后的调用栈
+pub(crate) fn write_stack(out: &mut dyn Write, frames: &[(usize, Symbolized)]) -> io::Result<()> {
+    if frames.is_empty() {
+        return writeln!(out, "    <no callstack>");
+    }
+
+    let mut index = 0;
+    for (addr, symbolized) in frames {
+        let module = match &symbolized.module {
+            Some((path, offset)) => format!("({path}+{offset:#x})"),
+            None => String::from("(<unknown module>)"),
+        };
+        if symbolized.frames.is_empty() {
+            writeln!(out, "    #{index} {addr:#018x} in ?? {module}")?;
+            index += 1;
+            continue;
+        }
+        for frame in &symbolized.frames {
+            let function = frame.function.as_deref().unwrap_or("??");
+            match (&frame.file, frame.line) {
+                (Some(file), Some(line)) => {
+                    writeln!(out, "    #{index} {addr:#018x} in {function} {file}:{line}")?
+                }
+                (Some(file), None) => {
+                    writeln!(out, "    #{index} {addr:#018x} in {function} {file}")?
+                }
+                _ => writeln!(out, "    #{index} {addr:#018x} in {function} {module}")?,
+            }
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+/// 输出按分配线程汇总的存活块，字节数多的线程在前
+fn write_threads(
+    out: &mut dyn Write,
+    by_thread: HashMap<NameId, (usize, usize)>,
+) -> io::Result<()> {
+    if by_thread.is_empty() {
+        return Ok(());
+    }
+    let mut by_thread: Vec<(NameId, (usize, usize))> = by_thread.into_iter().collect();
+    by_thread.sort_by_key(|&(_, (_, bytes))| Reverse(bytes));
+    writeln!(out)?;
+    writeln!(out, "[memleak] Outstanding by thread:")?;
+    writeln!(out, "   blocks      bytes  thread")?;
+    for (thread, (blocks, bytes)) in by_thread {
+        writeln!(
+            out,
+            "{blocks:>9} {bytes:>10}  {}",
+            threads::name(thread).to_string_lossy()
+        )?;
+    }
+    Ok(())
+}
+
+fn write_report(out: &mut dyn Write) -> io::Result<()> {
+    let mut symbolizer = Symbolizer::new();
+    let mut suppressions = Suppressions::load();
+
+    // 先符号化所有分配点，排除被屏蔽的部分后再统计
+    let mut leaks = Vec::new();
+    let (mut blocks, mut bytes) = (0, 0);
+    let mut totals: BTreeMap<Reachability, (usize, usize)> = BTreeMap::new();
+    let mut by_thread: HashMap<NameId, (usize, usize)> = HashMap::new();
+    for site in collect_sites() {
+        let frames = symbolize_stack(&mut symbolizer, site.stack);
+        if suppressions.check(&frames, site.blocks, site.bytes) {
+            continue;
+        }
+        blocks += site.blocks;
+        bytes += site.bytes;
+        if let Some(reachability) = site.reachability {
+            let total = totals.entry(reachability).or_default();
+            total.0 += site.blocks;
+            total.1 += site.bytes;
+        }
+        for (&thread, &(blocks, bytes)) in &site.threads {
+            let total = by_thread.entry(thread).or_default();
+            total.0 += blocks;
+            total.1 += bytes;
+        }
+        leaks.push((site, frames));
+    }
+
+    writeln!(
+        out,
+        "[memleak] ==== Leak report (pid {}) ====",
+        std::process::id()
+    )?;
+    writeln!(
+        out,
+        "[memleak] {blocks} blocks ({bytes} bytes) still allocated at exit, {} allocation sites",
+        leaks.len()
+    )?;
+    sampling::write_note(out)?;
+    if config::reachability_scan() {
+        reachability::write_note(out)?;
+    }
+    for (reachability, (blocks, bytes)) in &totals {
+        writeln!(
+            out,
+            "[memleak]   {}: {blocks} blocks ({bytes} bytes)",
+            reachability.label()
+        )?;
+    }
+    write_threads(out, by_thread)?;
+
+    for (site, frames) in leaks.iter().take(TOP_SITES) {
+        writeln!(out)?;
+        match site.reachability {
+            Some(reachability) => writeln!(
+                out,
+                "{} bytes in {} blocks are {}, allocated from:",
+                site.bytes,
+                site.blocks,
+                reachability.label()
+            )?,
+            None => writeln!(
+                out,
+                "{} bytes in {} blocks allocated from:",
+                site.bytes, site.blocks
+            )?,
+        }
+        write_stack(out, frames)?;
+    }
+    if leaks.len() > TOP_SITES {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "[memleak] {} more sites omitted",
+            leaks.len() - TOP_SITES
+        )?;
+    }
+    suppressions.write_stats(out)?;
+    out.flush()
+}
+
+/// 输出泄漏报告，调用者需要已经停用所有hook
+pub(crate) fn write() {
+    let target = config::report_target();
+    if let ReportTarget::Disabled = target {
+        return;
+    }
+    let result = open_output(target).and_then(|mut out| write_report(&mut *out));
+    if let Err(err) = result {
+        eprintln!("[memleak] Failed to write leak report: {err}");
+    }
+}
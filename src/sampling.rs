@@ -0,0 +1,88 @@
+//! 按字节间隔的泊松采样
+//!
+//! 与tcmalloc/jemalloc相同：每个线程维护一个距离下次采样还剩的字节数，
+//! 从均值为采样间隔N的指数分布中抽取，每次分配扣除请求大小，扣到0时采样该块。
+//! 大小为s的块被采样的概率为1 - exp(-s/N)，被采样的块带上这个概率的倒数作为权重，
+//! 报告和曲线中按权重累加得到的总量是无偏估计。
+
+use crate::config;
+use std::cell::Cell;
+use std::io::{self, Write};
+
+thread_local! {
+    /// 距离下次采样还剩的字节数，负数表示尚未抽取
+    static REMAINING: Cell<i64> = const { Cell::new(-1) };
+    /// xorshift随机数状态，0表示尚未初始化
+    static RNG: Cell<u64> = const { Cell::new(0) };
+}
+
+/// 下一个[0, 1)区间内的均匀随机数
+pub(crate) fn next_uniform() -> f64 {
+    RNG.with(|rng| {
+        let mut state = rng.get();
+        if state == 0 {
+            // 用线程局部变量的地址和时间作为种子，保证各线程的序列不同
+            state = (rng as *const Cell<u64> as u64) ^ crate::registry::now_ns() | 1;
+        }
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        rng.set(state);
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+/// 从均值为`interval`的指数分布中抽取下一次采样的字节间隔
+fn next_interval(interval: usize) -> i64 {
+    let distance = -(1.0 - next_uniform()).ln() * interval as f64;
+    (distance as i64).max(1)
+}
+
+/// 把期望值`weight`随机取整，保持无偏：以小数部分为概率向上取整
+fn round_weight(weight: f64) -> u32 {
+    let floor = weight.floor();
+    let rounded = if next_uniform() < weight - floor {
+        floor + 1.0
+    } else {
+        floor
+    };
+    rounded.clamp(1.0, u32::MAX as f64) as u32
+}
+
+/// 决定是否采样一次`size`字节的分配，采样时返回该块的权重
+///
+/// 未开启采样时每个块都被采样，权重为1。
+#[inline]
+pub(crate) fn sample(size: usize) -> Option<u32> {
+    let interval = config::sample_interval();
+    if interval == 0 {
+        return Some(1);
+    }
+    REMAINING.with(|remaining| {
+        let mut left = remaining.get();
+        if left < 0 {
+            left = next_interval(interval);
+        }
+        left -= size as i64;
+        if left > 0 {
+            remaining.set(left);
+            return None;
+        }
+        // 能走到这里的块大小一定大于0，采样概率不为0
+        remaining.set(next_interval(interval));
+        let probability = -(-(size as f64) / interval as f64).exp_m1();
+        Some(round_weight(1.0 / probability))
+    })
+}
+
+/// 开启采样时在报告中注明数字为估计值
+pub(crate) fn write_note(out: &mut dyn Write) -> io::Result<()> {
+    let interval = config::sample_interval();
+    if interval == 0 {
+        return Ok(());
+    }
+    writeln!(
+        out,
+        "[memleak] Sampling 1 allocation per {interval} bytes, totals are estimates"
+    )
+}
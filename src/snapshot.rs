@@ -0,0 +1,236 @@
+//! 堆快照与快照对比
+//!
+//! 每个存活块都记录分配时所处的快照纪元：拍摄编号为N的快照后，新分配的块纪元为N。
+//! 快照按(调用栈, 纪元)汇总存活块，因此对比快照A、B时既能得到每个分配点的
+//! 字节数和块数增量，也能得到B中哪些块是在A之后才分配的。
+//! 只保留最近的[`MAX_SNAPSHOTS`]次快照，更早的快照被丢弃，无法再参与对比。
+
+use crate::dump::output_path;
+use crate::registry;
+use crate::report::{symbolize_stack, write_stack};
+use crate::sampling;
+use crate::stack::StackId;
+use crate::symbolize::Symbolizer;
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+/// 当前快照纪元，即最近一次拍摄的快照编号；0表示尚未拍摄过快照
+static EPOCH: AtomicU32 = AtomicU32::new(0);
+
+/// 同一分配点、同一纪元的存活块汇总
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    blocks: usize,
+    bytes: usize,
+}
+
+/// 一次快照：按(调用栈, 纪元)汇总的存活块
+type Snapshot = HashMap<(StackId, u32), Usage>;
+
+/// 保留的快照数量
+pub(crate) const MAX_SNAPSHOTS: usize = 64;
+
+/// 最近拍摄的快照，最后一个的编号等于当前纪元
+static SNAPSHOTS: Mutex<VecDeque<Snapshot>> = Mutex::new(VecDeque::new());
+
+/// 当前纪元，新分配的块记录该值
+#[inline]
+pub(crate) fn current_epoch() -> u32 {
+    EPOCH.load(Ordering::Relaxed)
+}
+
+/// 拍摄一次快照，返回快照编号（从1开始）
+///
+/// 需要在hook的临界区内调用。
+pub(crate) fn take() -> u32 {
+    let mut snapshots = SNAPSHOTS.lock().unwrap();
+    // 先推进纪元再复制存活表：两者之间分配的块会同时出现在本快照中并被视为新块，
+    // 宁可多报也不漏报
+    let id = EPOCH.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut snapshot = Snapshot::new();
+    for (_, block) in registry::snapshot() {
+        let usage = snapshot.entry((block.stack, block.epoch)).or_default();
+        usage.blocks += block.estimated_blocks();
+        usage.bytes += block.estimated_bytes();
+    }
+    if snapshots.len() == MAX_SNAPSHOTS {
+        snapshots.pop_front();
+    }
+    snapshots.push_back(snapshot);
+    id
+}
+
+/// 一个分配点在两次快照间的变化
+struct SiteDiff {
+    stack: StackId,
+    before: Usage,
+    after: Usage,
+    /// 快照B中在快照A之后才分配的块
+    added: Usage,
+}
+
+impl SiteDiff {
+    fn byte_delta(&self) -> i64 {
+        self.after.bytes as i64 - self.before.bytes as i64
+    }
+
+    fn block_delta(&self) -> i64 {
+        self.after.blocks as i64 - self.before.blocks as i64
+    }
+}
+
+/// 按分配点对比两次快照
+fn diff_sites(before: &Snapshot, after: &Snapshot, from: u32) -> Vec<SiteDiff> {
+    let mut sites: HashMap<StackId, SiteDiff> = HashMap::new();
+    let site = |stack| SiteDiff {
+        stack,
+        before: Usage::default(),
+        after: Usage::default(),
+        added: Usage::default(),
+    };
+    for (&(stack, _), usage) in before {
+        let entry = sites.entry(stack).or_insert_with(|| site(stack));
+        entry.before.blocks += usage.blocks;
+        entry.before.bytes += usage.bytes;
+    }
+    for (&(stack, epoch), usage) in after {
+        let entry = sites.entry(stack).or_insert_with(|| site(stack));
+        entry.after.blocks += usage.blocks;
+        entry.after.bytes += usage.bytes;
+        if epoch >= from {
+            entry.added.blocks += usage.blocks;
+            entry.added.bytes += usage.bytes;
+        }
+    }
+    let mut sites: Vec<SiteDiff> = sites.into_values().collect();
+    sites.sort_by_key(|site| Reverse(site.byte_delta().abs()));
+    sites
+}
+
+fn write_section(
+    out: &mut dyn Write,
+    symbolizer: &mut Symbolizer,
+    title: &str,
+    sites: &[&SiteDiff],
+) -> io::Result<()> {
+    if sites.is_empty() {
+        return Ok(());
+    }
+    writeln!(out)?;
+    writeln!(out, "[memleak] ---- {title} ({}) ----", sites.len())?;
+    for site in sites {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:+} bytes ({:+} blocks): {} -> {} bytes, {} -> {} blocks, {} blocks ({} bytes) new, allocated from:",
+            site.byte_delta(),
+            site.block_delta(),
+            site.before.bytes,
+            site.after.bytes,
+            site.before.blocks,
+            site.after.blocks,
+            site.added.blocks,
+            site.added.bytes,
+        )?;
+        write_stack(out, &symbolize_stack(symbolizer, site.stack))?;
+    }
+    Ok(())
+}
+
+fn write_diff_to(
+    out: &mut dyn Write,
+    from: u32,
+    to: u32,
+    before: &Snapshot,
+    after: &Snapshot,
+) -> io::Result<()> {
+    let sites = diff_sites(before, after, from);
+    let total = |snapshot: &Snapshot| {
+        snapshot
+            .values()
+            .fold(Usage::default(), |total, usage| Usage {
+                blocks: total.blocks + usage.blocks,
+                bytes: total.bytes + usage.bytes,
+            })
+    };
+    let (before_total, after_total) = (total(before), total(after));
+
+    let new: Vec<&SiteDiff> = sites
+        .iter()
+        .filter(|site| site.before.blocks == 0 && site.after.blocks > 0)
+        .collect();
+    let grown: Vec<&SiteDiff> = sites
+        .iter()
+        .filter(|site| site.before.blocks > 0 && site.byte_delta() > 0)
+        .collect();
+    let shrunk: Vec<&SiteDiff> = sites.iter().filter(|site| site.byte_delta() < 0).collect();
+
+    writeln!(
+        out,
+        "[memleak] ==== Heap diff (pid {}): snapshot {from} -> {to} ====",
+        std::process::id()
+    )?;
+    writeln!(
+        out,
+        "[memleak] {} -> {} bytes ({:+}), {} -> {} blocks ({:+})",
+        before_total.bytes,
+        after_total.bytes,
+        after_total.bytes as i64 - before_total.bytes as i64,
+        before_total.blocks,
+        after_total.blocks,
+        after_total.blocks as i64 - before_total.blocks as i64,
+    )?;
+    sampling::write_note(out)?;
+    writeln!(
+        out,
+        "[memleak] {} new sites, {} grown sites, {} shrunk sites",
+        new.len(),
+        grown.len(),
+        shrunk.len()
+    )?;
+
+    let mut symbolizer = Symbolizer::new();
+    write_section(out, &mut symbolizer, "New sites", &new)?;
+    write_section(out, &mut symbolizer, "Grown sites", &grown)?;
+    write_section(out, &mut symbolizer, "Shrunk sites", &shrunk)?;
+    out.flush()
+}
+
+/// 把快照`from`到`to`的对比写到文件，未指定路径时使用带时间戳的默认文件名
+///
+/// 调用者需要处于hook的临界区内。
+pub(crate) fn write_diff(from: u32, to: u32, path: Option<&Path>) -> io::Result<PathBuf> {
+    let snapshots = SNAPSHOTS.lock().unwrap();
+    // 最早保留的快照编号
+    let first = current_epoch() + 1 - snapshots.len() as u32;
+    let get = |id: u32| {
+        id.checked_sub(first)
+            .and_then(|index| snapshots.get(index as usize))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no snapshot {id}")))
+    };
+    let (before, after) = (get(from)?, get(to)?);
+    let path = path.map_or_else(
+        || output_path(&format!("-diff-{from}-{to}")),
+        Path::to_path_buf,
+    );
+    let mut out = BufWriter::new(File::create(&path)?);
+    write_diff_to(&mut out, from, to, before, after)?;
+    Ok(path)
+}
+
+/// 锁住快照列表，直到返回值被丢弃，供fork期间保持快照的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(SNAPSHOTS.lock().unwrap())
+}
+
+/// 丢弃所有快照，纪元从头开始
+pub(crate) fn clear() {
+    SNAPSHOTS.lock().unwrap().clear();
+    EPOCH.store(0, Ordering::Relaxed);
+}
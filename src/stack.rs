@@ -0,0 +1,182 @@
+//! 调用栈采集与去重
+//!
+//! 泄漏报告需要按分配点聚合，因此每次分配都会采集一份调用栈，
+//! 相同的调用栈只保存一份，分配记录中只保存其编号。
+//! 仓库按调用栈的哈希分片，查找已有的调用栈时不分配内存。
+
+use crate::config;
+use libc::{c_int, c_void, dl_phdr_info, size_t};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, BuildHasherDefault};
+use std::sync::{Mutex, OnceLock};
+
+/// 调用栈编号，0表示没有调用栈
+pub(crate) type StackId = u32;
+
+/// 单次采集的最大帧数（包含需要跳过的本库帧）
+pub(crate) const MAX_FRAMES: usize = 80;
+
+/// 为栈顶的本库帧预留的帧数：先按调用栈深度加上这么多帧采集，不够时再完整采集一次
+const OWN_FRAMES: usize = 8;
+
+/// 调用栈仓库的分片数量，必须是2的幂
+const SHARD_COUNT: usize = 16;
+
+/// 调用栈仓库的一个分片：相同的调用栈只保存一份
+///
+/// 调用栈按哈希分到各个分片，编号中的低位是分片序号，高位是分片内的序号。
+struct Depot {
+    ids: HashMap<Box<[usize]>, StackId, BuildHasherDefault<DefaultHasher>>,
+    stacks: Vec<Box<[usize]>>,
+}
+
+static DEPOT: [Mutex<Depot>; SHARD_COUNT] = [const {
+    Mutex::new(Depot {
+        ids: HashMap::with_hasher(BuildHasherDefault::new()),
+        stacks: Vec::new(),
+    })
+}; SHARD_COUNT];
+
+/// 本库在进程地址空间中的范围[start, end)
+static SELF_RANGE: OnceLock<(usize, usize)> = OnceLock::new();
+
+unsafe extern "C" fn find_self_range(
+    info: *mut dl_phdr_info,
+    _size: size_t,
+    data: *mut c_void,
+) -> c_int {
+    let info = &*info;
+    let target = find_self_range as *const () as usize;
+    let phdrs = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    let (mut start, mut end) = (usize::MAX, 0);
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == libc::PT_LOAD) {
+        let segment_start = info.dlpi_addr as usize + phdr.p_vaddr as usize;
+        start = start.min(segment_start);
+        end = end.max(segment_start + phdr.p_memsz as usize);
+    }
+    if start <= target && target < end {
+        *(data as *mut (usize, usize)) = (start, end);
+        1
+    } else {
+        0
+    }
+}
+
+/// 判断地址是否位于本库（包括静态链接进来的Tracy）内部
+pub(crate) fn is_own_address(addr: usize) -> bool {
+    let &(start, end) = SELF_RANGE.get_or_init(|| {
+        let mut range = (0usize, 0usize);
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(find_self_range),
+                &mut range as *mut (usize, usize) as *mut c_void,
+            );
+        }
+        range
+    });
+    start <= addr && addr < end
+}
+
+/// 栈顶连续属于本库的帧数
+fn own_prefix(frames: &[usize]) -> usize {
+    frames
+        .iter()
+        .take_while(|&&frame| is_own_address(frame))
+        .count()
+}
+
+/// 采集最多`limit`帧返回地址，返回实际帧数
+fn backtrace(buffer: &mut [usize; MAX_FRAMES], limit: usize) -> usize {
+    let count = unsafe { libc::backtrace(buffer.as_mut_ptr() as *mut *mut c_void, limit as c_int) };
+    count.max(0) as usize
+}
+
+/// 采集当前调用栈并返回其编号
+///
+/// 栈顶属于本库的帧会被跳过，按配置的调用栈深度截断，只展开需要的帧数。
+/// 如果跳过之后的帧中仍有本库的帧，说明这是本库（例如Tracy启动线程时）
+/// 自身发起的分配，返回None。
+pub(crate) fn capture() -> Option<StackId> {
+    let depth = config::callstack_depth() as usize;
+    if depth == 0 {
+        return Some(0);
+    }
+
+    let mut buffer = [0usize; MAX_FRAMES];
+    let limit = (depth + OWN_FRAMES).min(MAX_FRAMES);
+    let mut count = backtrace(&mut buffer, limit);
+    let mut skip = own_prefix(&buffer[..count]);
+    if count == limit && count - skip < depth {
+        // 本库的帧比预留的多，展开全部帧
+        count = backtrace(&mut buffer, MAX_FRAMES);
+        skip = own_prefix(&buffer[..count]);
+    }
+    let frames = &buffer[skip..count.min(skip + depth)];
+    if frames.iter().any(|&frame| is_own_address(frame)) {
+        return None;
+    }
+    if frames.is_empty() {
+        return Some(0);
+    }
+    Some(intern(frames))
+}
+
+/// 查找或登记一条调用栈，只在第一次见到时复制
+fn intern(frames: &[usize]) -> StackId {
+    let hash = BuildHasherDefault::<DefaultHasher>::default().hash_one(frames);
+    let index = (hash >> 32) as usize & (SHARD_COUNT - 1);
+    let mut depot = DEPOT[index].lock().unwrap();
+    if let Some(&id) = depot.ids.get(frames) {
+        return id;
+    }
+    let frames: Box<[usize]> = frames.into();
+    depot.stacks.push(frames.clone());
+    let id = ((depot.stacks.len() - 1) * SHARD_COUNT + index + 1) as StackId;
+    depot.ids.insert(frames, id);
+    id
+}
+
+/// 根据编号取出调用栈的返回地址
+pub(crate) fn frames(id: StackId) -> Vec<usize> {
+    if id == 0 {
+        return Vec::new();
+    }
+    let index = id as usize - 1;
+    DEPOT[index & (SHARD_COUNT - 1)].lock().unwrap().stacks[index / SHARD_COUNT].to_vec()
+}
+
+/// 把调用栈的返回地址复制到`out`，返回复制的帧数；仓库被锁住时返回None
+///
+/// 不分配内存也不等待锁，可以在信号处理函数中调用。
+pub(crate) fn try_frames(id: StackId, out: &mut [usize]) -> Option<usize> {
+    if id == 0 {
+        return Some(0);
+    }
+    let index = id as usize - 1;
+    let depot = DEPOT[index & (SHARD_COUNT - 1)].try_lock().ok()?;
+    let frames = depot.stacks.get(index / SHARD_COUNT)?;
+    let count = frames.len().min(out.len());
+    out[..count].copy_from_slice(&frames[..count]);
+    Some(count)
+}
+
+/// 采集当前调用栈的返回地址，跳过栈顶属于本库的帧
+///
+/// 不分配内存，`backtrace`第一次调用时会加载展开库，之后可以在信号处理函数中调用。
+pub(crate) fn backtrace_raw(buffer: &mut [usize; MAX_FRAMES]) -> &[usize] {
+    let count = backtrace(buffer, MAX_FRAMES);
+    let skip = own_prefix(&buffer[..count]);
+    &buffer[skip..count]
+}
+
+/// 锁住调用栈仓库，直到返回值被丢弃，供fork期间保持仓库的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new(
+        DEPOT
+            .iter()
+            .map(|depot| depot.lock().unwrap())
+            .collect::<Vec<_>>(),
+    )
+}
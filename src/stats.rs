@@ -0,0 +1,127 @@
+//! 堆使用统计与Tracy曲线
+//!
+//! hook中维护几个原子计数器，后台线程按固定间隔把它们作为Tracy曲线发布：
+//! 存活字节数、存活块数、每秒分配次数、每秒释放次数以及字节数峰值。
+//! 计数器只统计进入存活分配表的块，与泄漏报告口径一致；开启采样时按块的权重累加。
+
+use crate::registry::Block;
+use crate::{config, enter_critical_section, tracy_active};
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracy_client::sys::{
+    ___tracy_connected, ___tracy_emit_plot_config, ___tracy_emit_plot_int,
+    TracyPlotFormatEnum_TracyPlotFormatMemory, TracyPlotFormatEnum_TracyPlotFormatNumber,
+};
+
+/// 存活字节数
+static BYTES_IN_USE: AtomicI64 = AtomicI64::new(0);
+/// 存活块数
+static BLOCKS_IN_USE: AtomicI64 = AtomicI64::new(0);
+/// 累计分配次数
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+/// 累计释放次数
+static FREES: AtomicU64 = AtomicU64::new(0);
+/// 存活字节数的峰值
+static PEAK_BYTES: AtomicI64 = AtomicI64::new(0);
+
+const BYTES_PLOT: &CStr = c"memleak: bytes in use";
+const BLOCKS_PLOT: &CStr = c"memleak: blocks in use";
+const ALLOCS_PLOT: &CStr = c"memleak: allocs/s";
+const FREES_PLOT: &CStr = c"memleak: frees/s";
+const PEAK_PLOT: &CStr = c"memleak: peak bytes";
+
+/// 记录一个进入存活分配表的块
+#[inline]
+pub(crate) fn record_alloc(block: &Block) {
+    let size = block.estimated_bytes() as i64;
+    let count = block.estimated_blocks();
+    let bytes = BYTES_IN_USE.fetch_add(size, Ordering::Relaxed) + size;
+    BLOCKS_IN_USE.fetch_add(count as i64, Ordering::Relaxed);
+    ALLOCS.fetch_add(count as u64, Ordering::Relaxed);
+    PEAK_BYTES.fetch_max(bytes, Ordering::Relaxed);
+}
+
+/// 记录一个离开存活分配表的块
+#[inline]
+pub(crate) fn record_free(block: &Block) {
+    let count = block.estimated_blocks();
+    BYTES_IN_USE.fetch_sub(block.estimated_bytes() as i64, Ordering::Relaxed);
+    BLOCKS_IN_USE.fetch_sub(count as i64, Ordering::Relaxed);
+    FREES.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// 清零所有计数器，fork后的子进程中与存活分配表一起重置
+pub(crate) fn clear() {
+    for counter in [&BYTES_IN_USE, &BLOCKS_IN_USE, &PEAK_BYTES] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    ALLOCS.store(0, Ordering::Relaxed);
+    FREES.store(0, Ordering::Relaxed);
+}
+
+/// 向Tracy声明各条曲线的显示格式
+///
+/// 按需连接模式下未连接时发出的配置会被丢弃，每次建立连接后都要重新发送。
+unsafe fn configure_plots() {
+    let memory = TracyPlotFormatEnum_TracyPlotFormatMemory as i32;
+    let number = TracyPlotFormatEnum_TracyPlotFormatNumber as i32;
+    ___tracy_emit_plot_config(BYTES_PLOT.as_ptr(), memory, 1, 1, 0);
+    ___tracy_emit_plot_config(PEAK_PLOT.as_ptr(), memory, 1, 0, 0);
+    ___tracy_emit_plot_config(BLOCKS_PLOT.as_ptr(), number, 1, 1, 0);
+    ___tracy_emit_plot_config(ALLOCS_PLOT.as_ptr(), number, 0, 0, 0);
+    ___tracy_emit_plot_config(FREES_PLOT.as_ptr(), number, 0, 0, 0);
+}
+
+/// 后台发布线程
+fn publisher(interval: Duration) {
+    // 本线程的分配属于本库自身，始终不追踪
+    enter_critical_section();
+    let mut configured = false;
+    let mut last = (
+        Instant::now(),
+        ALLOCS.load(Ordering::Relaxed),
+        FREES.load(Ordering::Relaxed),
+    );
+    loop {
+        std::thread::sleep(interval);
+        let now = Instant::now();
+        let allocs = ALLOCS.load(Ordering::Relaxed);
+        let frees = FREES.load(Ordering::Relaxed);
+        let elapsed = now.duration_since(last.0).as_secs_f64();
+        let per_second =
+            |count: u64, previous: u64| (count.wrapping_sub(previous) as f64 / elapsed) as i64;
+        let (alloc_rate, free_rate) = (per_second(allocs, last.1), per_second(frees, last.2));
+        last = (now, allocs, frees);
+
+        if !tracy_active() || unsafe { ___tracy_connected() } == 0 {
+            configured = false;
+            continue;
+        }
+        unsafe {
+            if !configured {
+                configure_plots();
+                configured = true;
+            }
+            ___tracy_emit_plot_int(BYTES_PLOT.as_ptr(), BYTES_IN_USE.load(Ordering::Relaxed));
+            ___tracy_emit_plot_int(BLOCKS_PLOT.as_ptr(), BLOCKS_IN_USE.load(Ordering::Relaxed));
+            ___tracy_emit_plot_int(PEAK_PLOT.as_ptr(), PEAK_BYTES.load(Ordering::Relaxed));
+            ___tracy_emit_plot_int(ALLOCS_PLOT.as_ptr(), alloc_rate);
+            ___tracy_emit_plot_int(FREES_PLOT.as_ptr(), free_rate);
+        }
+    }
+}
+
+/// 启动后台发布线程，发布间隔为0时不启动
+pub(crate) fn install() {
+    let interval = config::plot_interval();
+    if interval.is_zero() {
+        return;
+    }
+    let spawned = std::thread::Builder::new()
+        .name("memleak-plots".into())
+        .spawn(move || publisher(interval));
+    if let Err(err) = spawned {
+        eprintln!("[memleak] Failed to start plot thread: {err}");
+    }
+}
@@ -0,0 +1,240 @@
+//! 泄漏屏蔽规则
+//!
+//! 规则文件由`MEMLEAK_SUPPRESSIONS`指定，格式与LeakSanitizer相同：
+//! 每行一条`leak:<模式>`，`#`开头的行为注释。模式与调用栈中任意一帧的
+//! 函数名、源文件或模块路径匹配时，该分配点不计入泄漏报告。
+//! 模式中`*`匹配任意字符串，未以`^`/`$`锚定时按子串匹配。
+
+use crate::config;
+use crate::symbolize::Symbolized;
+use std::io::{self, Write};
+
+/// 一条屏蔽规则及其命中统计
+struct Rule {
+    pattern: String,
+    /// 命中的分配点数量
+    sites: usize,
+    /// 命中的堆块数量
+    blocks: usize,
+    /// 命中的字节数
+    bytes: usize,
+}
+
+/// 已加载的屏蔽规则
+pub(crate) struct Suppressions {
+    rules: Vec<Rule>,
+}
+
+impl Suppressions {
+    /// 从`MEMLEAK_SUPPRESSIONS`指定的文件加载规则
+    pub(crate) fn load() -> Self {
+        let mut rules = Vec::new();
+        if let Some(path) = config::suppressions_path() {
+            match std::fs::read_to_string(path) {
+                Ok(content) => rules = parse(&content),
+                Err(err) => eprintln!(
+                    "[memleak] Failed to read suppressions {}: {err}",
+                    path.display()
+                ),
+            }
+        }
+        Suppressions { rules }
+    }
+
+    /// 检查调用栈是否被屏蔽，命中时累计该规则的统计
+    pub(crate) fn check(
+        &mut self,
+        frames: &[(usize, Symbolized)],
+        blocks: usize,
+        bytes: usize,
+    ) -> bool {
+        let Some(rule) = self.rules.iter_mut().find(|rule| {
+            frames
+                .iter()
+                .any(|(_, frame)| matches_frame(&rule.pattern, frame))
+        }) else {
+            return false;
+        };
+        rule.sites += 1;
+        rule.blocks += blocks;
+        rule.bytes += bytes;
+        true
+    }
+
+    /// 输出每条规则的命中次数
+    pub(crate) fn write_stats(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        writeln!(out)?;
+        writeln!(out, "[memleak] Suppressions used:")?;
+        writeln!(out, "  sites   blocks      bytes  pattern")?;
+        for rule in &self.rules {
+            writeln!(
+                out,
+                "{:>7} {:>8} {:>10}  leak:{}",
+                rule.sites, rule.blocks, rule.bytes, rule.pattern
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// 解析规则文件
+fn parse(content: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some(("leak", pattern)) if !pattern.trim().is_empty() => rules.push(Rule {
+                pattern: pattern.trim().to_string(),
+                sites: 0,
+                blocks: 0,
+                bytes: 0,
+            }),
+            _ => eprintln!(
+                "[memleak] Ignoring invalid suppression at line {}: {line}",
+                number + 1
+            ),
+        }
+    }
+    rules
+}
+
+/// 模式是否与一个返回地址的任意符号信息匹配
+fn matches_frame(pattern: &str, symbolized: &Symbolized) -> bool {
+    if let Some((module, _)) = &symbolized.module {
+        if template_match(pattern, module) {
+            return true;
+        }
+    }
+    symbolized.frames.iter().any(|frame| {
+        frame
+            .function
+            .as_deref()
+            .is_some_and(|function| template_match(pattern, function))
+            || frame
+                .file
+                .as_deref()
+                .is_some_and(|file| template_match(pattern, file))
+    })
+}
+
+/// 按LeakSanitizer的规则匹配：`*`为通配符，`^`/`$`锚定开头/结尾
+pub(crate) fn template_match(pattern: &str, text: &str) -> bool {
+    Template::new(pattern).matches(text)
+}
+
+/// 预先按`*`拆分好的匹配模式，匹配时不分配内存
+pub(crate) struct Template {
+    anchor_start: bool,
+    anchor_end: bool,
+    parts: Vec<String>,
+}
+
+impl Template {
+    /// 解析模式，规则同`template_match`
+    pub(crate) fn new(pattern: &str) -> Self {
+        let (anchor_start, pattern) = match pattern.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (anchor_end, pattern) = match pattern.strip_suffix('$') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        Template {
+            anchor_start,
+            anchor_end,
+            parts: pattern.split('*').map(str::to_owned).collect(),
+        }
+    }
+
+    /// 文本是否与模式匹配
+    pub(crate) fn matches(&self, text: &str) -> bool {
+        let mut rest = text;
+        for (index, part) in self.parts.iter().enumerate() {
+            let first = index == 0;
+            let last = index == self.parts.len() - 1;
+            if part.is_empty() {
+                continue;
+            }
+            if first && self.anchor_start {
+                match rest.strip_prefix(part.as_str()) {
+                    Some(tail) => rest = tail,
+                    None => return false,
+                }
+            } else if last && self.anchor_end {
+                return rest.ends_with(part.as_str());
+            } else {
+                match rest.find(part.as_str()) {
+                    Some(pos) => rest = &rest[pos + part.len()..],
+                    None => return false,
+                }
+            }
+        }
+        // 以`*`结尾（最后一段为空）时，剩余部分任意
+        let trailing_star = self.parts.len() > 1 && self.parts.last().is_some_and(String::is_empty);
+        !self.anchor_end || rest.is_empty() || trailing_star
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::template_match;
+
+    #[test]
+    fn unanchored_pattern_matches_substring() {
+        assert!(template_match("alloc", "my_alloc_fn"));
+        assert!(template_match("my_alloc_fn", "my_alloc_fn"));
+        assert!(!template_match("alloc", "aloc"));
+        assert!(!template_match("free", "malloc"));
+    }
+
+    #[test]
+    fn anchors_pin_start_and_end() {
+        assert!(template_match("^foo", "foobar"));
+        assert!(!template_match("^foo", "xfoo"));
+        assert!(template_match("bar$", "foobar"));
+        assert!(!template_match("bar$", "barx"));
+        assert!(template_match("^foo$", "foo"));
+        assert!(!template_match("^foo$", "foobar"));
+        assert!(!template_match("^foo$", "xfoo"));
+    }
+
+    #[test]
+    fn empty_patterns() {
+        assert!(template_match("", "anything"));
+        assert!(template_match("", ""));
+        assert!(template_match("^", "anything"));
+        assert!(!template_match("$", "anything"));
+        assert!(template_match("^$", ""));
+        assert!(!template_match("^$", "x"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(template_match("^std::*::new$", "std::vec::Vec::new"));
+        assert!(!template_match("^std::*::new$", "std::vec::Vec::new_in"));
+        assert!(template_match("lib*.so", "/usr/lib/libfoo.so.6"));
+        assert!(template_match("^a*", "abc"));
+        assert!(template_match("a*$", "xab"));
+        assert!(!template_match("x*x$", "x"));
+        assert!(template_match("x*x$", "xx"));
+        assert!(!template_match("^ab*b$", "ab"));
+        assert!(template_match("^ab*b$", "abb"));
+    }
+
+    #[test]
+    fn consecutive_stars() {
+        assert!(template_match("a**b", "a-b"));
+        assert!(template_match("a**b", "ab"));
+        assert!(template_match("**", ""));
+        assert!(template_match("^**$", "anything"));
+        assert!(template_match("^a***$", "a"));
+        assert!(!template_match("^a***$", "ba"));
+    }
+}
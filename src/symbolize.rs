@@ -0,0 +1,224 @@
+//! 调用栈符号化
+//!
+//! 报告输出时把返回地址解析为函数名、文件和行号（包括内联帧）。
+//! 通过`dl_iterate_phdr`找到地址所属的模块，再读取该模块文件中的ELF/DWARF信息。
+//! 没有DWARF信息时退回到符号表，只采用范围覆盖该地址的函数符号，找不到时输出`??`和模块内偏移。
+//! 符号化过程会大量分配内存，只能在hook停用之后调用。
+
+use addr2line::Loader;
+use libc::{c_int, c_void, dl_phdr_info, size_t};
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+/// 已加载的模块
+struct Module {
+    /// 模块文件路径
+    path: PathBuf,
+    /// 加载偏移：运行时地址 - 文件中的虚拟地址
+    bias: usize,
+    /// 可执行段在运行时的地址范围
+    segments: Vec<(usize, usize)>,
+}
+
+/// 符号表中的一个函数符号
+struct Symbol {
+    /// 文件中的虚拟地址
+    address: u64,
+    /// 函数的字节数
+    size: u64,
+    name: String,
+}
+
+/// 读取模块文件中大小已知的函数符号，按地址排序
+///
+/// 同时读取完整符号表和动态符号表，被strip的模块只剩动态符号表。
+fn read_symbols(path: &Path) -> Vec<Symbol> {
+    let Ok(data) = std::fs::read(path) else {
+        return Vec::new();
+    };
+    let Ok(file) = object::File::parse(&*data) else {
+        return Vec::new();
+    };
+    let mut symbols: Vec<Symbol> = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|symbol| {
+            symbol.kind() == SymbolKind::Text && symbol.is_definition() && symbol.size() > 0
+        })
+        .filter_map(|symbol| {
+            Some(Symbol {
+                address: symbol.address(),
+                size: symbol.size(),
+                name: symbol.name().ok()?.to_owned(),
+            })
+        })
+        .collect();
+    symbols.sort_unstable_by_key(|symbol| symbol.address);
+    symbols
+}
+
+/// 查找范围`[address, address + size)`覆盖`probe`的函数符号
+fn find_symbol(symbols: &[Symbol], probe: u64) -> Option<&str> {
+    let index = symbols.partition_point(|symbol| symbol.address <= probe);
+    let symbol = symbols.get(index.checked_sub(1)?)?;
+    (probe < symbol.address + symbol.size).then_some(symbol.name.as_str())
+}
+
+/// 一个符号化后的帧，内联展开的函数各占一帧
+pub(crate) struct Frame {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// 一个返回地址的符号化结果
+pub(crate) struct Symbolized {
+    /// 所属模块路径及模块内偏移
+    pub module: Option<(String, usize)>,
+    /// 由内向外排列的帧，无调试信息时可能为空
+    pub frames: Vec<Frame>,
+}
+
+unsafe extern "C" fn collect_module(
+    info: *mut dl_phdr_info,
+    _size: size_t,
+    data: *mut c_void,
+) -> c_int {
+    let info = &*info;
+    let modules = &mut *(data as *mut Vec<Module>);
+    let name = if info.dlpi_name.is_null() {
+        ""
+    } else {
+        CStr::from_ptr(info.dlpi_name).to_str().unwrap_or("")
+    };
+    // 第一个模块是主程序，名称为空
+    let path = match name {
+        "" if modules.is_empty() => match std::fs::read_link("/proc/self/exe") {
+            Ok(path) => path,
+            Err(_) => return 0,
+        },
+        "" => return 0,
+        name => PathBuf::from(name),
+    };
+
+    let bias = info.dlpi_addr as usize;
+    let phdrs = std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    let segments = phdrs
+        .iter()
+        .filter(|phdr| phdr.p_type == libc::PT_LOAD && phdr.p_flags & libc::PF_X != 0)
+        .map(|phdr| {
+            let start = bias + phdr.p_vaddr as usize;
+            (start, start + phdr.p_memsz as usize)
+        })
+        .collect();
+    modules.push(Module {
+        path,
+        bias,
+        segments,
+    });
+    0
+}
+
+/// 符号化器，缓存每个模块已解析的调试信息
+pub(crate) struct Symbolizer {
+    modules: Vec<Module>,
+    loaders: HashMap<usize, Option<Loader>>,
+    /// 没有DWARF信息时用到的符号表，按需读取
+    symbols: HashMap<usize, Vec<Symbol>>,
+}
+
+impl Symbolizer {
+    /// 枚举当前进程加载的模块
+    pub(crate) fn new() -> Self {
+        let mut modules: Vec<Module> = Vec::new();
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(collect_module),
+                &mut modules as *mut Vec<Module> as *mut c_void,
+            );
+        }
+        Symbolizer {
+            modules,
+            loaders: HashMap::new(),
+            symbols: HashMap::new(),
+        }
+    }
+
+    /// 符号化一个返回地址
+    pub(crate) fn symbolize(&mut self, addr: usize) -> Symbolized {
+        let Some(index) = self.modules.iter().position(|module| {
+            module
+                .segments
+                .iter()
+                .any(|&(start, end)| start <= addr && addr < end)
+        }) else {
+            return Symbolized {
+                module: None,
+                frames: Vec::new(),
+            };
+        };
+
+        let module = &self.modules[index];
+        let offset = addr - module.bias;
+        let module_name = (module.path.to_string_lossy().into_owned(), offset);
+        let loader = self
+            .loaders
+            .entry(index)
+            .or_insert_with(|| Loader::new(&module.path).ok());
+        let Some(loader) = loader else {
+            return Symbolized {
+                module: Some(module_name),
+                frames: Vec::new(),
+            };
+        };
+
+        // 返回地址指向call的下一条指令，减1才能落在调用语句所在的行
+        let probe = offset.saturating_sub(1) as u64;
+        let mut frames = Vec::new();
+        if let Ok(mut iter) = loader.find_frames(probe) {
+            while let Ok(Some(frame)) = iter.next() {
+                let function = frame
+                    .function
+                    .as_ref()
+                    .and_then(|name| name.demangle().ok())
+                    .map(Cow::into_owned);
+                let (file, line) = match frame.location {
+                    Some(location) => (location.file.map(str::to_owned), location.line),
+                    None => (None, None),
+                };
+                frames.push(Frame {
+                    function,
+                    file,
+                    line,
+                });
+            }
+        }
+
+        // 没有DWARF信息时退回到符号表
+        if frames.iter().all(|frame| frame.function.is_none()) {
+            let symbols = self
+                .symbols
+                .entry(index)
+                .or_insert_with(|| read_symbols(&module.path));
+            if let Some(name) = find_symbol(symbols, probe) {
+                let function = addr2line::demangle_auto(Cow::Borrowed(name), None).into_owned();
+                match frames.first_mut() {
+                    Some(frame) => frame.function = Some(function),
+                    None => frames.push(Frame {
+                        function: Some(function),
+                        file: None,
+                        line: None,
+                    }),
+                }
+            }
+        }
+
+        Symbolized {
+            module: Some(module_name),
+            frames,
+        }
+    }
+}
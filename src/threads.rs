@@ -0,0 +1,185 @@
+//! 线程登记表
+//!
+//! 每个线程第一次经过分配hook时登记自己的线程号和线程指针，
+//! 线程退出时通过线程局部变量的析构自动注销。可达性扫描依赖这张表
+//! 找到各线程的栈和TLS。
+//!
+//! 栈范围在列出线程时才查询：`pthread_getattr_np`持有线程自身的锁时会调用`realloc`，
+//! 在hook中查询当前线程的栈会在这把锁上死锁（Rust程序的新线程启动时就会这样调用）。
+//!
+//! 线程名单独登记成一张表，存活块只记录线程名编号，按线程归属的报告和Tracy内存池
+//! 都通过编号查到名字。同名线程共用一个编号。
+
+use libc::{pid_t, pthread_t};
+use std::any::Any;
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+
+/// 一个存活线程的信息
+#[derive(Clone, Copy)]
+pub(crate) struct ThreadInfo {
+    /// 内核线程号
+    pub tid: pid_t,
+    /// pthread句柄，glibc中同时也是线程指针（TCB地址）
+    pub pthread: pthread_t,
+    /// 栈的地址范围[低, 高)，获取失败或尚未查询时为(0, 0)
+    pub stack: (usize, usize),
+}
+
+static THREADS: Mutex<Vec<ThreadInfo>> = Mutex::new(Vec::new());
+
+/// 线程登记凭据，析构时从登记表中移除
+struct Registration {
+    /// fork后子进程中的线程号会改变
+    tid: Cell<pid_t>,
+}
+
+impl Registration {
+    fn new() -> Self {
+        let info = current_info();
+        THREADS.lock().unwrap().push(info);
+        Registration {
+            tid: Cell::new(info.tid),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let tid = self.tid.get();
+        THREADS.lock().unwrap().retain(|info| info.tid != tid);
+    }
+}
+
+/// 当前线程的信息
+fn current_info() -> ThreadInfo {
+    unsafe {
+        ThreadInfo {
+            tid: libc::gettid(),
+            pthread: libc::pthread_self(),
+            stack: (0, 0),
+        }
+    }
+}
+
+thread_local! {
+    static REGISTRATION: Registration = Registration::new();
+}
+
+/// 查询线程的栈范围
+///
+/// 线程需要保证存活：登记表的锁被持有时，表中的线程无法完成注销，也就不会退出。
+unsafe fn stack_of(thread: pthread_t) -> (usize, usize) {
+    let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+    if libc::pthread_getattr_np(thread, &mut attr) != 0 {
+        return (0, 0);
+    }
+    let mut addr = std::ptr::null_mut();
+    let mut size = 0;
+    let ret = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+    libc::pthread_attr_destroy(&mut attr);
+    if ret != 0 {
+        return (0, 0);
+    }
+    (addr as usize, addr as usize + size)
+}
+
+/// 登记当前线程，重复调用没有额外开销
+///
+/// 需要在hook的临界区内调用：首次登记会分配内存。
+#[inline]
+pub(crate) fn register_current() {
+    // 线程退出过程中局部变量已经析构时忽略
+    let _ = REGISTRATION.try_with(|_| ());
+}
+
+/// 复制当前登记的所有线程，并查询它们的栈范围
+///
+/// 不能在hook中调用，也不能在持有登记表分片锁时调用：被查询的线程可能正在
+/// `pthread_getattr_np`中等待这些锁。
+pub(crate) fn list() -> Vec<ThreadInfo> {
+    let threads = THREADS.lock().unwrap();
+    threads
+        .iter()
+        .map(|&info| ThreadInfo {
+            stack: unsafe { stack_of(info.pthread) },
+            ..info
+        })
+        .collect()
+}
+
+/// 锁住登记表和线程名表，直到返回值被丢弃，供fork期间保持表的一致
+pub(crate) fn lock_for_fork() -> Box<dyn Any> {
+    Box::new((THREADS.lock().unwrap(), NAMES.lock().unwrap()))
+}
+
+/// fork后在子进程中调用：只有调用fork的线程被复制过来
+pub(crate) fn reset_after_fork() {
+    THREADS.lock().unwrap().clear();
+    let _ = REGISTRATION.try_with(|registration| {
+        // 刚刚创建的登记已经用新的线程号加入了登记表
+        let info = current_info();
+        if registration.tid.get() != info.tid {
+            registration.tid.set(info.tid);
+            THREADS.lock().unwrap().push(info);
+        }
+    });
+}
+
+/// 线程名编号，0表示未知
+pub(crate) type NameId = u32;
+
+/// 重新读取线程名的间隔（分配次数）：线程名通常在线程启动后才设置
+const NAME_REFRESH_INTERVAL: u32 = 1024;
+
+/// 已登记的线程名，下标为编号减一；名字永不释放，Tracy按指针区分内存池
+static NAMES: Mutex<Vec<&'static CStr>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// 当前线程的线程名编号，以及距离下次重新读取还剩的分配次数
+    static NAME_CACHE: Cell<(NameId, u32)> = const { Cell::new((0, 0)) };
+}
+
+/// 登记线程名，返回其编号
+fn intern(name: &CStr) -> NameId {
+    let mut names = NAMES.lock().unwrap();
+    if let Some(index) = names.iter().position(|&known| known == name) {
+        return index as NameId + 1;
+    }
+    names.push(Box::leak(CString::from(name).into_boxed_c_str()));
+    names.len() as NameId
+}
+
+/// 当前线程的线程名编号
+///
+/// 需要在hook的临界区内调用：首次登记线程名会分配内存。
+pub(crate) fn current_name() -> NameId {
+    NAME_CACHE.with(|cache| {
+        let (id, remaining) = cache.get();
+        if id != 0 && remaining > 0 {
+            cache.set((id, remaining - 1));
+            return id;
+        }
+        // Linux的线程名最长15字节
+        let mut buffer = [0 as libc::c_char; 16];
+        let ret = unsafe {
+            libc::pthread_getname_np(libc::pthread_self(), buffer.as_mut_ptr(), buffer.len())
+        };
+        let id = if ret == 0 {
+            intern(unsafe { CStr::from_ptr(buffer.as_ptr()) })
+        } else {
+            0
+        };
+        cache.set((id, NAME_REFRESH_INTERVAL));
+        id
+    })
+}
+
+/// 线程名编号对应的名字
+pub(crate) fn name(id: NameId) -> &'static CStr {
+    match id {
+        0 => c"<unknown>",
+        id => NAMES.lock().unwrap()[id as usize - 1],
+    }
+}